@@ -1,23 +1,52 @@
-use std::collections::HashMap;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::time::{Duration, Instant};
 
 use async_std::task;
+use chrono::Local;
 use rand::Rng;
+use rand::seq::SliceRandom;
 use regex::Regex;
 use reqwest::header;
 use serde::{Deserialize, Serialize};
+use serenity::builder::CreateActionRow;
 use serenity::client::Context;
+use serenity::http::Http;
 use serenity::model::channel::{Message, ReactionType};
 use serenity::model::guild::{Guild, GuildContainer};
-use serenity::model::id::EmojiId;
+use serenity::model::id::{ChannelId, EmojiId, UserId};
+use serenity::model::interactions::{InteractionApplicationCommandCallbackDataFlags, InteractionResponseType};
+use serenity::model::interactions::message_component::{ButtonStyle, MessageComponentInteraction};
 use serenity::model::user::User;
+use serenity::prelude::TypeMap;
 use serenity::utils::MessageBuilder;
 
-use crate::{BotState, Config, Draft, Maps, QueueMessages, ReadyQueue, State, StateContainer, SteamIdCache, TeamNameCache, UserQueue};
+use crate::{CommandMessage, Config, Db, Draft, GameServer, LiveMatch, LiveMatches, Lobbies, Lobby, Maps, QueuePopConfig, Series, ServerConfig, State, StateContainer, SteamIdCache, TeamNameCache, Veto};
+use crate::db::{Database, MapEntry, MatchPlayer, MatchRecord};
+use crate::game_server::{GameServerProvider, launch_match, MatchSettings};
+use crate::persistence::save_state;
+use crate::slash_commands;
+
+const DRAFT_PICK_PREFIX: &str = "draft_pick:";
+const VETO_PREFIX: &str = "veto:";
+const READY_PREFIX: &str = "ready:";
+const UNREADY_PREFIX: &str = "unready:";
+/// Discord allows 25 buttons on a message, which is also the number of letter emojis the map vote uses
+const MAX_MAPS: usize = 25;
+const REROLL_EMOJI: &str = "🔁";
+const REROLL_VOTE_TIME: Duration = Duration::from_secs(30);
+const MAP_VOTE_TIME: Duration = Duration::from_secs(60);
+const MAP_VOTE_WARNING: Duration = Duration::from_secs(10);
+const MAP_VOTE_POLL_INTERVAL: Duration = Duration::from_secs(3);
+/// How long a launched match keeps its server busy if its match end webhook never arrives
+const MATCH_TIMEOUT: Duration = Duration::from_secs(6 * 60 * 60);
+const SCRIMBOT_API_TIMEOUT: Duration = Duration::from_secs(5);
 
 struct ReactionResult {
-    count: u64,
     map: String,
+    emoji: String,
+    /// The queued players who reacted with `emoji`
+    voters: Vec<u64>,
 }
 
 #[allow(non_snake_case)]
@@ -37,7 +66,8 @@ struct Stats {
     winPercentage: f64,
 }
 
-pub(crate) async fn handle_join(context: &Context, msg: &Message, author: &User) {
+pub(crate) async fn handle_join(context: &Context, msg: &CommandMessage, author: &User) {
+    if !lobby_check(context, msg).await { return; }
     let mut data = context.data.write().await;
     let steam_id_cache: &HashMap<u64, String> = &data.get::<SteamIdCache>().unwrap();
     if !steam_id_cache.contains_key(author.id.as_u64()) {
@@ -52,17 +82,28 @@ pub(crate) async fn handle_join(context: &Context, msg: &Message, author: &User)
         }
         return;
     }
-    let user_queue: &mut Vec<User> = &mut data.get_mut::<UserQueue>().unwrap();
-    if user_queue.len() >= 10 {
-        let response = MessageBuilder::new()
-            .mention(author)
-            .push(" sorry but the queue is full.")
-            .build();
+    let queue_size = team_size(data.get::<Config>().unwrap()) * 2;
+    let lobby = lobby_mut(&mut data, msg.channel_id);
+    if lobby.user_queue.len() >= queue_size && !lobby.user_queue.contains(author) {
+        let response = if lobby.waitlist.contains(author) {
+            MessageBuilder::new()
+                .mention(author)
+                .push(" is already on the waitlist.")
+                .build()
+        } else {
+            lobby.waitlist.push(author.clone());
+            MessageBuilder::new()
+                .mention(author)
+                .push(format!(" the queue is full, you have been added to the waitlist at position {}.", lobby.waitlist.len()))
+                .build()
+        };
+        save_state(&data);
         if let Err(why) = msg.channel_id.say(&context.http, &response).await {
             eprintln!("Error sending message: {:?}", why);
         }
         return;
     }
+    let user_queue: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).user_queue;
     if user_queue.contains(&author) {
         let response = MessageBuilder::new()
             .mention(author)
@@ -74,16 +115,17 @@ pub(crate) async fn handle_join(context: &Context, msg: &Message, author: &User)
         return;
     }
     user_queue.push(author.clone());
+    let queue_len = user_queue.len();
+    lobby_mut(&mut data, msg.channel_id).waitlist.retain(|u| u != author);
     let response = MessageBuilder::new()
         .mention(author)
         .push(" has been added to the queue. Queue size: ")
-        .push(user_queue.len().to_string())
-        .push("/10")
+        .push(format!("{}/{}", queue_len, queue_size))
         .build();
     if let Err(why) = msg.channel_id.say(&context.http, &response).await {
         eprintln!("Error sending message: {:?}", why);
     }
-    let queued_msgs: &mut HashMap<u64, String> = data.get_mut::<QueueMessages>().unwrap();
+    let queued_msgs: &mut HashMap<u64, String> = &mut lobby_mut(&mut data, msg.channel_id).queue_messages;
     let quote_regex = Regex::new("[\"”“](.*?)[\"”“]").unwrap();
     if let Some(mat) = quote_regex.find(&msg.content) {
         let start = mat.start();
@@ -91,8 +133,14 @@ pub(crate) async fn handle_join(context: &Context, msg: &Message, author: &User)
         end = end.min(start + 50);
         queued_msgs.insert(*msg.author.id.as_u64(), String::from(msg.content[start..end].trim()));
     }
-    let config: &Config = data.get::<Config>().unwrap();
-    if let Some(role_id) = config.discord.assign_role_id {
+    save_state(&data);
+    let pop = if queue_len == queue_size { queue_pop(&data, msg.channel_id) } else { None };
+    let assign_role_id = data.get::<Config>().unwrap().discord.assign_role_id;
+    drop(data);
+    if let Some(pop) = pop {
+        queue_popped(context, msg.channel_id, pop).await;
+    }
+    if let Some(role_id) = assign_role_id {
         if let Ok(value) = msg.author.has_role(&context.http, msg.guild_id.unwrap(), role_id).await {
             if !value {
                 let guild = Guild::get(&context.http, msg.guild_id.unwrap()).await.unwrap();
@@ -107,14 +155,23 @@ pub(crate) async fn handle_join(context: &Context, msg: &Message, author: &User)
     }
 }
 
-pub(crate) async fn handle_leave(context: Context, msg: Message) {
+pub(crate) async fn handle_leave(context: Context, msg: CommandMessage) {
+    if !lobby_check(&context, &msg).await { return; }
     let mut data = context.data.write().await;
-    let state: &mut StateContainer = data.get_mut::<BotState>().unwrap();
+    let waitlist: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).waitlist;
+    if waitlist.contains(&msg.author) {
+        waitlist.retain(|u| u != &msg.author);
+        save_state(&data);
+        send_simple_tagged_msg(&context, &msg, " has left the waitlist.", &msg.author).await;
+        return;
+    }
+    let state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
     if state.state != State::Queue {
         send_simple_tagged_msg(&context, &msg, " cannot `.leave` the queue after `.start`, use `.cancel` to start over if needed.", &msg.author).await;
         return;
     }
-    let user_queue: &mut Vec<User> = data.get_mut::<UserQueue>().unwrap();
+    let queue_size = team_size(data.get::<Config>().unwrap()) * 2;
+    let user_queue: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).user_queue;
     if !user_queue.contains(&msg.author) {
         let response = MessageBuilder::new()
             .mention(&msg.author)
@@ -130,22 +187,120 @@ pub(crate) async fn handle_leave(context: Context, msg: Message) {
     let response = MessageBuilder::new()
         .mention(&msg.author)
         .push(" has left the queue. Queue size: ")
-        .push(user_queue.len().to_string())
-        .push("/10")
+        .push(format!("{}/{}", user_queue.len(), queue_size))
         .build();
     if let Err(why) = msg.channel_id.say(&context.http, &response).await {
         eprintln!("Error sending message: {:?}", why);
     }
-    let queued_msgs: &mut HashMap<u64, String> = data.get_mut::<QueueMessages>().unwrap();
+    let queued_msgs: &mut HashMap<u64, String> = &mut lobby_mut(&mut data, msg.channel_id).queue_messages;
     if queued_msgs.get(&msg.author.id.as_u64()).is_some() {
         queued_msgs.remove(&msg.author.id.as_u64());
     }
+    let promoted = promote_waitlist(&mut data, msg.channel_id);
+    save_state(&data);
+    announce_promoted(&context.http, msg.channel_id, &promoted, queue_size, &data).await;
+    let pop = if promoted.is_empty() { None } else { queue_pop(&data, msg.channel_id) };
+    drop(data);
+    if let Some(pop) = pop {
+        queue_popped(&context, msg.channel_id, pop).await;
+    }
 }
 
-pub(crate) async fn handle_list(context: Context, msg: Message) {
+/// A full queue to notify, collected while the lock is held so the messages can be sent once it's released
+struct QueuePop {
+    user_queue: Vec<User>,
+    queue_pop: QueuePopConfig,
+}
+
+/// The queue to notify if it is full and `queue_pop` is configured
+fn queue_pop(data: &TypeMap, channel_id: ChannelId) -> Option<QueuePop> {
+    let config: &Config = data.get::<Config>().unwrap();
+    let queue_pop = config.queue_pop.as_ref()?;
+    let lobby = lobby(data, channel_id);
+    if lobby.user_queue.len() != team_size(config) * 2 || lobby.state.state != State::Queue { return None; }
+    Some(QueuePop { user_queue: lobby.user_queue.clone(), queue_pop: queue_pop.clone() })
+}
+
+/// Notifies the players once the queue is full and schedules the automatic `.start`, the lock must not be held
+async fn queue_popped(context: &Context, channel_id: ChannelId, pop: QueuePop) {
+    let QueuePop { user_queue, queue_pop } = pop;
+    let mut response = MessageBuilder::new();
+    for user in &user_queue {
+        response.mention(user).push(" ");
+    }
+    response.push_bold_line("the queue is full!");
+    match queue_pop.auto_start_delay {
+        Some(delay) => response.push(format!("Scrim setup starts automatically in {} seconds, `.leave` now if you can't play.", delay)),
+        None => response.push("An admin can now `.start` the scrim."),
+    };
+    if let Err(why) = channel_id.say(&context.http, response.build()).await {
+        eprintln!("Error sending message: {:?}", why);
+    }
+    if queue_pop.dm_players.unwrap_or(true) {
+        let content = format!("The queue in <#{}> is full, your scrim is about to start!", channel_id);
+        for user in &user_queue {
+            if let Err(why) = user.direct_message(&context.http, |m| m.content(&content)).await {
+                eprintln!("Error sending queue pop dm to {}: {:?}", user.name, why);
+            }
+        }
+    }
+    if let Some(delay) = queue_pop.auto_start_delay {
+        let options = queue_pop.auto_start_options.unwrap_or_default();
+        tokio::spawn(auto_start(context.clone(), channel_id, user_queue, delay, options));
+    }
+}
+
+/// Begins the `.start` flow on behalf of the bot once the grace period is over,
+/// unless the setup was already started or the queue changed in the meantime
+async fn auto_start(context: Context, channel_id: ChannelId, popped: Vec<User>, delay: u64, options: String) {
+    task::sleep(Duration::from_secs(delay)).await;
+    {
+        let data = context.data.read().await;
+        match data.get::<Lobbies>().unwrap().get(channel_id.as_u64()) {
+            // a queue that filled up again after someone left gets its own grace period
+            Some(lobby) if lobby.state.state == State::Queue && lobby.user_queue == popped => {}
+            _ => return,
+        }
+    }
+    let msg = CommandMessage {
+        author: User::from(context.cache.current_user().await),
+        channel_id,
+        guild_id: context.cache.guild_channel(channel_id).await.map(|c| c.guild_id),
+        content: format!(".start {}", options).trim_end().to_string(),
+        mentions: Vec::new(),
+    };
+    start_setup(context, msg).await;
+}
+
+/// Moves players from the front of the waitlist into the queue while there is room, returning who was moved
+fn promote_waitlist(data: &mut TypeMap, channel_id: ChannelId) -> Vec<User> {
+    let queue_size = team_size(data.get::<Config>().unwrap()) * 2;
+    let lobby = lobby_mut(data, channel_id);
+    let count = queue_size.saturating_sub(lobby.user_queue.len()).min(lobby.waitlist.len());
+    let promoted: Vec<User> = lobby.waitlist.drain(..count).collect();
+    lobby.user_queue.extend(promoted.iter().cloned());
+    promoted
+}
+
+async fn announce_promoted(http: &Http, channel_id: ChannelId, promoted: &[User], queue_size: usize, data: &TypeMap) {
+    if promoted.is_empty() { return; }
+    let mut response = MessageBuilder::new();
+    for user in promoted {
+        response.mention(user).push(" ");
+    }
+    let response = response
+        .push(format!("moved up from the waitlist into the queue. Queue size: {}/{}", lobby(data, channel_id).user_queue.len(), queue_size))
+        .build();
+    if let Err(why) = channel_id.say(http, &response).await {
+        eprintln!("Error sending message: {:?}", why);
+    }
+}
+
+pub(crate) async fn handle_list(context: Context, msg: CommandMessage) {
+    if !lobby_check(&context, &msg).await { return; }
     let data = context.data.write().await;
-    let user_queue: &Vec<User> = data.get::<UserQueue>().unwrap();
-    let queue_msgs: &HashMap<u64, String> = data.get::<QueueMessages>().unwrap();
+    let user_queue: &Vec<User> = &lobby(&data, msg.channel_id).user_queue;
+    let queue_msgs: &HashMap<u64, String> = &lobby(&data, msg.channel_id).queue_messages;
     let mut user_name = String::new();
     for u in user_queue {
         user_name.push_str(format!("\n- @{}", u.name).as_str());
@@ -153,23 +308,43 @@ pub(crate) async fn handle_list(context: Context, msg: Message) {
             user_name.push_str(format!(": `{}`", value).as_str());
         }
     }
-    let response = MessageBuilder::new()
+    let config: &Config = data.get::<Config>().unwrap();
+    let lobby_name = config.lobbies.iter()
+        .flatten()
+        .find(|l| l.channel_id == *msg.channel_id.as_u64())
+        .and_then(|l| l.name.as_ref());
+    let mut response = MessageBuilder::new();
+    if let Some(lobby_name) = lobby_name {
+        response.push_bold(lobby_name).push(" - ");
+    }
+    response
         .push("Current queue size: ")
-        .push(&user_queue.len())
-        .push("/10")
-        .push(user_name)
-        .build();
+        .push(format!("{}/{}", user_queue.len(), team_size(config) * 2))
+        .push(user_name);
+    let waitlist: &Vec<User> = &lobby(&data, msg.channel_id).waitlist;
+    if !waitlist.is_empty() {
+        let waitlist_names: String = waitlist.iter()
+            .enumerate()
+            .map(|(i, u)| format!("\n{}. @{}", i + 1, u.name))
+            .collect();
+        response.push_line("").push("Waitlist:").push(waitlist_names);
+    }
+    let response = response.build();
 
     if let Err(why) = msg.channel_id.say(&context.http, &response).await {
         eprintln!("Error sending message: {:?}", why);
     }
 }
 
-pub(crate) async fn handle_clear(context: Context, msg: Message) {
+pub(crate) async fn handle_clear(context: Context, msg: CommandMessage) {
     if !admin_check(&context, &msg, true).await { return; }
+    if !lobby_check(&context, &msg).await { return; }
     let mut data = context.data.write().await;
-    let user_queue: &mut Vec<User> = &mut data.get_mut::<UserQueue>().unwrap();
+    let user_queue: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).user_queue;
     user_queue.clear();
+    let waitlist: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).waitlist;
+    waitlist.clear();
+    save_state(&data);
     let response = MessageBuilder::new()
         .mention(&msg.author)
         .push(" cleared queue")
@@ -179,30 +354,35 @@ pub(crate) async fn handle_clear(context: Context, msg: Message) {
     }
 }
 
-pub(crate) async fn handle_help(context: Context, msg: Message) {
+pub(crate) async fn handle_help(context: Context, msg: CommandMessage) {
     let mut commands = String::from("
 **Commands:**
-`.join` - Join the queue, add an optional message in quotes (max 50 characters) i.e. `.join \"available at 9pm\"`
-`.leave` - Leave the queue
-`.list` - List all users in the queue
+`.join` - Join the queue, or the waitlist once the queue is full. Add an optional message in quotes (max 50 characters) i.e. `.join \"available at 9pm\"`
+`.leave` - Leave the queue, or the waitlist
+`.list` - List all users in the queue and the waitlist
 `.steamid` - Set your steamID i.e. `.steamid STEAM_0:1:12345678`
 `.maps` - Lists all maps in available for play
 `.stats` - Lists all available statistics for user. Add `Xm` to display past X months where X is a single digit integer. Add `.top10` to display top 10 ranking with an optional `.top10 Xm` month filter.
 `.teamname` - Sets a custom team name when you are a captain i.e. `.teamname TeamName`
+`.history` - Lists recently played matches, add a number to show more i.e. `.history 10`
+`.match` - Shows the teams, sides and result of a match from `.history` i.e. `.match 12`
 
 _These are commands used during the `.start` process:_
 `.captain` - Add yourself as a captain.
-`.pick` - If you are a captain, this is used to pick a player
-`.ready` - After the draft phase is completed, use this to ready up
+`.pick` - If you are a captain, this is used to pick a player i.e. `.pick @user`, or press the player's button on the draft message
+`.ban` - If you are a captain, this is used to ban a map during the map veto i.e. `.ban de_dust2`, in a BO3 maps are picked with `.pick de_dust2`
+`.ready` - After the draft phase is completed, use this or the Ready button sent to you in a DM to ready up
 `.unready` - After the draft phase is completed, use this to cancel your `.ready` status
 `.readylist` - Lists players not readied up
 ");
     let admin_commands = String::from("
 _These are admin commands:_
-`.start` - Start the match setup process
+`.start` - Start the match setup process, use `.start balanced` to skip the draft and balance teams by rating or `.start random` for random teams. Add `veto` for a map veto between the captains instead of the map vote or `bo3` to play a best of three series i.e. `.start random bo3`
 `.kick` - Kick a player by mentioning them i.e. `.kick @user`
-`.addmap` - Add a map to the map vote i.e. `.addmap de_dust2` _Note: map must be present on the server or the server will not start._
+`.sub` - Replace a player after `.start` i.e. `.sub @out @in`, captains can also use this for players on their own team
+`.addmap` - Add a map to the map vote i.e. `.addmap de_dust2`, add a Steam Workshop id and/or a display name with `.addmap de_cache 123456789 \"Cache\"` _Note: maps without a workshop id must be present on the server or the server will not start._
 `.removemap` - Remove a map from the map vote i.e. `.removemap de_dust2`
+`.disablemap` - Leave a map out of map votes without removing it i.e. `.disablemap de_dust2`, `.enablemap` puts it back
 `.recoverqueue` - Manually set a queue, tag all users to add after the command
 `.clear` - Clear the queue
 `.cancel` - Cancels `.start` process
@@ -222,24 +402,27 @@ _These are admin commands:_
     }
 }
 
-pub(crate) async fn handle_recover_queue(context: Context, msg: Message) {
+pub(crate) async fn handle_recover_queue(context: Context, msg: CommandMessage) {
     if !admin_check(&context, &msg, true).await { return; }
+    if !lobby_check(&context, &msg).await { return; }
     {
         let mut data = context.data.write().await;
-        let user_queue: &mut Vec<User> = &mut data.get_mut::<UserQueue>().unwrap();
+        let user_queue: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).user_queue;
         user_queue.clear();
-        let queue_msgs: &mut HashMap<u64, String> = &mut data.get_mut::<QueueMessages>().unwrap();
+        let queue_msgs: &mut HashMap<u64, String> = &mut lobby_mut(&mut data, msg.channel_id).queue_messages;
         queue_msgs.clear();
+        save_state(&data);
     }
     for mention in &msg.mentions {
         handle_join(&context, &msg, &mention).await
     }
 }
 
-pub(crate) async fn handle_ready_list(context: Context, msg: Message) {
+pub(crate) async fn handle_ready_list(context: Context, msg: CommandMessage) {
+    if !lobby_check(&context, &msg).await { return; }
     let data = context.data.write().await;
-    let ready_queue: &Vec<User> = data.get::<ReadyQueue>().unwrap();
-    let user_queue: &Vec<User> = data.get::<UserQueue>().unwrap();
+    let ready_queue: &Vec<User> = &lobby(&data, msg.channel_id).ready_queue;
+    let user_queue: &Vec<User> = &lobby(&data, msg.channel_id).user_queue;
     let user_name: String = user_queue
         .iter()
         .filter(|user| !ready_queue.contains(user))
@@ -255,21 +438,25 @@ pub(crate) async fn handle_ready_list(context: Context, msg: Message) {
     }
 }
 
-pub(crate) async fn handle_start(context: Context, msg: Message) {
+pub(crate) async fn handle_start(context: Context, msg: CommandMessage) {
     let admin_check = admin_check(&context, &msg, true).await;
+    if !lobby_check(&context, &msg).await { return; }
     if !admin_check { return; }
+    start_setup(context, msg).await;
+}
+
+/// The `.start` flow after the permission checks, shared with the automatic start when the queue fills
+async fn start_setup(context: Context, msg: CommandMessage) {
+    let args: Vec<String> = msg.content.trim().to_lowercase().split(' ').skip(1).map(String::from).collect();
+    let mode = args.iter().find(|a| *a == "balanced" || *a == "random").cloned().unwrap_or_default();
     let mut data = context.data.write().await;
-    let bot_state: &StateContainer = data.get::<BotState>().unwrap();
+    let bot_state: &StateContainer = &lobby(&data, msg.channel_id).state;
     if bot_state.state != State::Queue {
         send_simple_tagged_msg(&context, &msg, " `.start` command has already been entered", &msg.author).await;
         return;
     }
-    let user_queue: &mut Vec<User> = data.get_mut::<UserQueue>().unwrap();
-    if !user_queue.contains(&msg.author) && !admin_check {
-        send_simple_tagged_msg(&context, &msg, " non-admin users that are not in the queue cannot start the match", &msg.author).await;
-        return;
-    }
-    if user_queue.len() != 10 {
+    let queue_size = team_size(data.get::<Config>().unwrap()) * 2;
+    if lobby(&data, msg.channel_id).user_queue.len() != queue_size {
         let response = MessageBuilder::new()
             .mention(&msg.author)
             .push(" the queue is not full yet")
@@ -279,6 +466,26 @@ pub(crate) async fn handle_start(context: Context, msg: Message) {
         }
         return;
     }
+    let veto = args.iter().any(|a| a == "veto" || a == "bo3");
+    let best_of_three = args.iter().any(|a| a == "bo3");
+    if veto && map_pool(&data).len() < if best_of_three { 3 } else { 2 } {
+        send_simple_tagged_msg(&context, &msg, " not enough maps in the map pool for a veto", &msg.author).await;
+        return;
+    }
+    if !veto && vote_maps(&data).0.is_empty() {
+        send_simple_tagged_msg(&context, &msg, " there are no maps in the map pool to vote on", &msg.author).await;
+        return;
+    }
+    let config: &Config = data.get::<Config>().unwrap();
+    if best_of_three && (config.webhook.is_none() || config.dathost.as_ref().and_then(|d| d.match_end_url.as_ref()).is_none()) {
+        send_simple_tagged_msg(&context, &msg, " a BO3 series needs the match end `webhook` and `dathost.match_end_url` to be configured", &msg.author).await;
+        return;
+    }
+    if best_of_three && !data.get::<GameServer>().unwrap().reports_match_end() {
+        send_simple_tagged_msg(&context, &msg, " a BO3 series needs a game server provider with match end webhooks, i.e. DatHost", &msg.author).await;
+        return;
+    }
+    let user_queue: &Vec<User> = &lobby(&data, msg.channel_id).user_queue;
     let user_queue_mention: String = user_queue
         .iter()
         .map(|user| format!("- <@{}>\n", user.id))
@@ -290,9 +497,70 @@ pub(crate) async fn handle_start(context: Context, msg: Message) {
     if let Err(why) = msg.channel_id.say(&context.http, &response).await {
         eprintln!("Error sending message: {:?}", why);
     }
-    let bot_state: &mut StateContainer = data.get_mut::<BotState>().unwrap();
-    bot_state.state = State::MapPick;
-    let maps: &Vec<String> = &data.get::<Maps>().unwrap();
+    if veto {
+        let veto = Veto::new(&map_pool(&data), best_of_three);
+        lobby_mut(&mut data, msg.channel_id).veto = Some(veto);
+    } else {
+        lobby_mut(&mut data, msg.channel_id).veto = None;
+        let bot_state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
+        bot_state.state = State::MapPick;
+        save_state(&data);
+        drop(data);
+        let map = match map_vote(&context, &msg).await {
+            Some(map) => map,
+            None => return,
+        };
+        data = context.data.write().await;
+        // `.cancel` may have been used while the lock was released
+        if lobby(&data, msg.channel_id).state.state != State::MapPick { return; }
+        lobby_mut(&mut data, msg.channel_id).draft.map = map;
+    }
+    let bot_state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
+    bot_state.state = State::CaptainPick;
+    let draft: &mut Draft = &mut lobby_mut(&mut data, msg.channel_id).draft;
+    draft.captain_a = None;
+    draft.captain_b = None;
+    draft.team_a = Vec::new();
+    draft.team_b = Vec::new();
+    if mode == "balanced" {
+        save_state(&data);
+        let user_queue = lobby(&data, msg.channel_id).user_queue.clone();
+        let sources = rating_sources(&data, &user_queue);
+        drop(data);
+        let ratings = player_ratings(&user_queue, sources).await;
+        data = context.data.write().await;
+        // `.cancel` may have been used while the lock was released
+        if lobby(&data, msg.channel_id).state.state != State::CaptainPick { return; }
+        let lobby_ref = lobby(&data, msg.channel_id);
+        if lobby_ref.user_queue != user_queue || lobby_ref.draft.captain_a.is_some() {
+            send_simple_msg(&context, &msg, "The lobby changed while the players were rated, pick captains with `.captain` instead.").await;
+            return;
+        }
+        balance_teams(&context, &msg, &mut data, user_queue, ratings).await;
+        save_state(&data);
+        return;
+    }
+    if mode == "random" {
+        let bot_state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
+        bot_state.state = State::RandomTeams;
+        save_state(&data);
+        drop(data);
+        random_teams(&context, &msg).await;
+        return;
+    }
+    save_state(&data);
+    send_simple_msg(&context, &msg, "Starting captain pick phase. Two users type `.captain` to start picking teams.").await;
+}
+
+/// Runs the reaction vote for the map, ties are broken at random. The lock is released while waiting for votes
+/// and the vote ends early once every queued player has voted. Returns `None` if the `.start` was cancelled meanwhile.
+async fn map_vote(context: &Context, msg: &CommandMessage) -> Option<String> {
+    let (maps, excluded_maps, vote_time, votes_per_player) = {
+        let data = context.data.read().await;
+        let config: &Config = data.get::<Config>().unwrap();
+        let (maps, excluded_maps) = vote_maps(&data);
+        (maps, excluded_maps, map_vote_time(config), config.map_votes_per_player.unwrap_or(1))
+    };
     let mut unicode_to_maps: HashMap<String, String> = HashMap::new();
     let a_to_z = ('a'..'z').collect::<Vec<_>>();
     let unicode_emoji_map = populate_unicode_emojis().await;
@@ -305,42 +573,87 @@ pub(crate) async fn handle_start(context: Context, msg: Message) {
         .enumerate()
         .map(|(i, c)| format!(":regional_indicator_{}: `{}`\n", c, &maps[i]))
         .collect();
-    let response = MessageBuilder::new()
-        .push_bold_line("Map Vote:")
-        .push(vote_text)
-        .build();
+    let mut response = MessageBuilder::new();
+    response
+        .push_bold_line(format!("Map Vote ({} vote{} per player):", votes_per_player, if votes_per_player == 1 { "" } else { "s" }))
+        .push(vote_text);
+    if !excluded_maps.is_empty() {
+        let excluded: Vec<String> = excluded_maps.iter().map(|m| format!("`{}`", m)).collect();
+        response.push_line(format!("_Recently played: {}_", excluded.join(", ")));
+    }
+    let response = response.build();
     let vote_msg = msg.channel_id.say(&context.http, &response).await.unwrap();
     for c in emoji_suffixes {
         vote_msg.react(&context.http, ReactionType::Unicode(String::from(unicode_emoji_map.get(&c).unwrap()))).await.unwrap();
     }
-    task::sleep(Duration::from_secs(50)).await;
+    let vote_start = Instant::now();
+    let mut warned = vote_time <= MAP_VOTE_WARNING;
+    let mut reactions: HashMap<String, (u64, Vec<User>)> = HashMap::new();
+    let mut warned_votes: HashMap<u64, Vec<String>> = HashMap::new();
+    let user_queue = loop {
+        task::sleep(MAP_VOTE_POLL_INTERVAL).await;
+        let user_queue: Vec<User> = {
+            let data = context.data.read().await;
+            let lobby = lobby(&data, msg.channel_id);
+            if lobby.state.state != State::MapPick { return None; }
+            lobby.user_queue.clone()
+        };
+        let mut results = map_vote_results(context, &vote_msg, &unicode_to_maps, &user_queue, &mut reactions).await;
+        let over_limit = players_to_warn(&results, votes_per_player, &mut warned_votes);
+        if !over_limit.is_empty() {
+            remove_votes(context, &vote_msg, &mut results, &mut reactions, &over_limit).await;
+            let mut response = MessageBuilder::new();
+            for voter in &over_limit {
+                response.push(format!("<@{}> ", voter));
+            }
+            response.push(format!("you can only vote for {} map{}, your votes were removed so you can vote again",
+                                  votes_per_player, if votes_per_player == 1 { "" } else { "s" }));
+            if let Err(why) = msg.channel_id.say(&context.http, response.build()).await {
+                eprintln!("Error sending message: {:?}", why);
+            }
+        }
+        let voters: HashSet<u64> = results.iter().flat_map(|r| r.voters.iter().copied()).collect();
+        let elapsed = vote_start.elapsed();
+        if elapsed >= vote_time || voters.len() >= user_queue.len() {
+            break user_queue;
+        }
+        if !warned && vote_time - elapsed <= MAP_VOTE_WARNING {
+            warned = true;
+            let response = MessageBuilder::new()
+                .push(format!("Voting will end in {} seconds", (vote_time - elapsed).as_secs()))
+                .build();
+            if let Err(why) = msg.channel_id.say(&context.http, &response).await {
+                eprintln!("Error sending message: {:?}", why);
+            }
+        }
+    };
+    // a vote changed between two polls without changing any reaction count is only picked up by a full recount
+    reactions.clear();
+    let mut results = map_vote_results(context, &vote_msg, &unicode_to_maps, &user_queue, &mut reactions).await;
+    let over_limit = over_vote_limit(&results, votes_per_player);
+    for result in results.iter_mut() {
+        result.voters.retain(|v| !over_limit.contains(v));
+    }
+    let mut tally: Vec<&ReactionResult> = results.iter().filter(|r| !r.voters.is_empty()).collect();
+    tally.sort_by(|x, y| y.voters.len().cmp(&x.voters.len()).then(x.map.cmp(&y.map)));
+    let tally_text: String = tally.iter()
+        .map(|r| format!("`{}` - {} vote{}\n", r.map, r.voters.len(), if r.voters.len() == 1 { "" } else { "s" }))
+        .collect();
     let response = MessageBuilder::new()
-        .push("Voting will end in 10 seconds")
+        .push_bold_line("Map vote results:")
+        .push(if tally_text.is_empty() { String::from("No votes were cast\n") } else { tally_text })
         .build();
     if let Err(why) = msg.channel_id.say(&context.http, &response).await {
         eprintln!("Error sending message: {:?}", why);
     }
-    task::sleep(Duration::from_secs(10)).await;
-    let updated_vote_msg = vote_msg.channel_id.message(&context.http, vote_msg.id).await.unwrap();
-    let mut results: Vec<ReactionResult> = Vec::new();
-    for reaction in updated_vote_msg.reactions {
-        let react_as_map: Option<&String> = unicode_to_maps.get(reaction.reaction_type.to_string().as_str());
-        if react_as_map != None {
-            let map = String::from(react_as_map.unwrap());
-            results.push(ReactionResult {
-                count: reaction.count,
-                map,
-            });
-        }
-    }
     let max_count = results
         .iter()
-        .max_by(|x, y| x.count.cmp(&y.count))
-        .unwrap()
-        .count;
+        .map(|r| r.voters.len())
+        .max()
+        .unwrap();
     let final_results: Vec<ReactionResult> = results
         .into_iter()
-        .filter(|m| m.count == max_count)
+        .filter(|m| m.voters.len() == max_count)
         .collect();
     let mut selected_map = String::from("");
     if final_results.len() > 1 {
@@ -366,43 +679,361 @@ pub(crate) async fn handle_start(context: Context, msg: Message) {
         }
         selected_map.push_str(map);
     }
+    Some(selected_map)
+}
+
+/// The queued players who voted for each map, reactions from anyone else are ignored.
+/// `reactions` holds the reaction count and users of each emoji from the previous poll, the users of an emoji are
+/// only fetched again once its count changed, so a poll costs a single request while nobody is voting.
+async fn map_vote_results(context: &Context, vote_msg: &Message, unicode_to_maps: &HashMap<String, String>, user_queue: &[User],
+                          reactions: &mut HashMap<String, (u64, Vec<User>)>) -> Vec<ReactionResult> {
+    let counts: Option<HashMap<String, u64>> = match vote_msg.channel_id.message(&context.http, vote_msg.id).await {
+        Ok(message) => Some(message.reactions.iter()
+            .filter_map(|r| match &r.reaction_type {
+                ReactionType::Unicode(emoji) => Some((String::from(emoji), r.count)),
+                _ => None,
+            })
+            .collect()),
+        Err(why) => {
+            eprintln!("Error reading map votes: {:?}", why);
+            None
+        }
+    };
+    let mut results = Vec::new();
+    for (emoji, map) in unicode_to_maps {
+        let count = counts.as_ref().map(|counts| counts.get(emoji).copied().unwrap_or(0));
+        let changed = match (count, reactions.get(emoji)) {
+            (Some(count), Some((previous_count, _))) => count != *previous_count,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if changed {
+            match vote_msg.reaction_users(&context.http, ReactionType::Unicode(String::from(emoji)), Some(100), None).await {
+                Ok(users) => { reactions.insert(String::from(emoji), (count.unwrap_or(0), users)); }
+                Err(why) => eprintln!("Error reading map votes: {:?}", why),
+            }
+        }
+        let voters = reactions.get(emoji)
+            .map(|(_, users)| users.iter()
+                .filter(|u| user_queue.contains(u))
+                .map(|u| *u.id.as_u64())
+                .collect())
+            .unwrap_or_default();
+        results.push(ReactionResult { map: String::from(map), emoji: String::from(emoji), voters });
+    }
+    results
+}
+
+/// The players who voted for more maps than they are allowed to
+fn over_vote_limit(results: &[ReactionResult], votes_per_player: usize) -> Vec<u64> {
+    let mut vote_counts: HashMap<u64, usize> = HashMap::new();
+    for voter in results.iter().flat_map(|r| &r.voters) {
+        *vote_counts.entry(*voter).or_insert(0) += 1;
+    }
+    vote_counts.into_iter()
+        .filter(|(_, count)| *count > votes_per_player)
+        .map(|(voter, _)| voter)
+        .collect()
+}
+
+/// The players over the vote limit who haven't been warned about their current votes yet. `warned_votes` holds
+/// the votes each player had when they were warned, so a player whose votes couldn't be removed isn't warned on every poll.
+fn players_to_warn(results: &[ReactionResult], votes_per_player: usize, warned_votes: &mut HashMap<u64, Vec<String>>) -> Vec<u64> {
+    let votes_of = |voter: u64| -> Vec<String> {
+        results.iter()
+            .filter(|r| r.voters.contains(&voter))
+            .map(|r| String::from(&r.map))
+            .collect()
+    };
+    warned_votes.retain(|voter, votes| *votes == votes_of(*voter));
+    let mut to_warn = Vec::new();
+    for voter in over_vote_limit(results, votes_per_player) {
+        if let Entry::Vacant(entry) = warned_votes.entry(voter) {
+            entry.insert(votes_of(voter));
+            to_warn.push(voter);
+        }
+    }
+    to_warn
+}
+
+/// Removes all of the given players' reactions from the vote, they no longer count towards the result.
+/// The users of the affected emojis are fetched again on the next poll, in case removing a reaction failed.
+async fn remove_votes(context: &Context, vote_msg: &Message, results: &mut [ReactionResult],
+                      reactions: &mut HashMap<String, (u64, Vec<User>)>, voters: &[u64]) {
+    for result in results.iter_mut() {
+        let mut removed = false;
+        for voter in result.voters.iter().filter(|v| voters.contains(v)) {
+            removed = true;
+            if let Err(why) = vote_msg.channel_id.delete_reaction(&context.http, vote_msg.id, Some(UserId(*voter)), ReactionType::Unicode(String::from(&result.emoji))).await {
+                eprintln!("Error removing map vote: {:?}", why);
+            }
+        }
+        if removed {
+            reactions.remove(&result.emoji);
+        }
+        result.voters.retain(|v| !voters.contains(v));
+    }
+}
+
+/// The maps offered in the map vote and the recently played maps left out of it.
+/// A random subset of the remaining maps is offered when `map_vote_size` is configured.
+fn vote_maps(data: &TypeMap) -> (Vec<String>, Vec<String>) {
     let config: &Config = data.get::<Config>().unwrap();
-    let client = reqwest::Client::new();
-    let dathost_username = &config.dathost.username;
-    let dathost_password: Option<String> = Some(String::from(&config.dathost.password));
-    let update_map_url = format!("https://dathost.net/api/0.1/game-servers/{}", &config.server.id);
+    let maps = map_pool(data);
+    let recent_maps = match config.exclude_recent_maps {
+        Some(count) if count > 0 => data.get::<Db>().unwrap().recent_maps(count).unwrap_or_else(|why| {
+            eprintln!("Error reading recently played maps: {:?}", why);
+            Vec::new()
+        }),
+        _ => Vec::new(),
+    };
+    let (mut vote_maps, mut excluded_maps): (Vec<String>, Vec<String>) = maps.iter()
+        .cloned()
+        .partition(|m| !recent_maps.contains(m));
+    // never leave less than two maps to vote on
+    if vote_maps.len() < 2 {
+        vote_maps = maps.clone();
+        excluded_maps.clear();
+    }
+    if let Some(size) = config.map_vote_size {
+        if vote_maps.len() > size {
+            vote_maps.shuffle(&mut rand::thread_rng());
+            vote_maps.truncate(size);
+            // keep the map pool's order so the emojis stay in a familiar order
+            vote_maps.sort_by_key(|m| maps.iter().position(|p| p == m));
+        }
+    }
+    (vote_maps, excluded_maps)
+}
+
+/// How long the map vote lasts, 60 seconds unless `map_vote_time` is configured
+fn map_vote_time(config: &Config) -> Duration {
+    config.map_vote_time.map(Duration::from_secs).unwrap_or(MAP_VOTE_TIME)
+}
+
+/// Shuffles the queue into two teams and gives the players one chance to vote for a re-roll.
+/// The lock is released during the vote so the rest of the bot keeps working.
+async fn random_teams(context: &Context, msg: &CommandMessage) {
+    let reroll_emoji = ReactionType::Unicode(String::from(REROLL_EMOJI));
+    let teams_msg = {
+        let mut data = context.data.write().await;
+        shuffle_teams(&mut data, msg.channel_id);
+        save_state(&data);
+        post_random_teams(context, msg, &data, "Teams have been randomized:").await
+    };
+    if let Some(teams_msg) = &teams_msg {
+        if let Err(why) = teams_msg.react(&context.http, reroll_emoji.clone()).await {
+            eprintln!("Error reacting with re-roll emoji: {:?}", why);
+        }
+        send_simple_msg(context, msg, &format!("React with {} within {} seconds to vote for a re-roll, more than half of the players have to agree.",
+                                               REROLL_EMOJI, REROLL_VOTE_TIME.as_secs())).await;
+        task::sleep(REROLL_VOTE_TIME).await;
+    }
+    let mut data = context.data.write().await;
+    // the setup may have been cancelled while the vote was open
+    if lobby(&data, msg.channel_id).state.state != State::RandomTeams { return; }
+    if let Some(teams_msg) = &teams_msg {
+        let user_queue: &Vec<User> = &lobby(&data, msg.channel_id).user_queue;
+        let votes = match teams_msg.reaction_users(&context.http, reroll_emoji, Some(100), None).await {
+            Ok(users) => users.iter().filter(|u| user_queue.contains(u)).count(),
+            Err(why) => {
+                eprintln!("Error reading re-roll votes: {:?}", why);
+                0
+            }
+        };
+        if votes * 2 > user_queue.len() {
+            shuffle_teams(&mut data, msg.channel_id);
+            post_random_teams(context, msg, &data, &format!("{} players voted for a re-roll, the new teams are:", votes)).await;
+        } else {
+            send_simple_msg(context, msg, "Not enough votes for a re-roll, the teams are final.").await;
+        }
+    }
+    teams_complete(context, msg, &mut data).await;
+    save_state(&data);
+}
+
+/// Splits the queue randomly into the two teams, the first player of each team becomes its captain
+fn shuffle_teams(data: &mut TypeMap, channel_id: ChannelId) {
+    let lobby = lobby_mut(data, channel_id);
+    let mut players = lobby.user_queue.clone();
+    players.shuffle(&mut rand::thread_rng());
+    let team_b = players.split_off(players.len() / 2);
+    let draft = &mut lobby.draft;
+    draft.captain_a = players.first().cloned();
+    draft.captain_b = team_b.first().cloned();
+    draft.team_a = players;
+    draft.team_b = team_b;
+    draft.current_picker = None;
+}
+
+async fn post_random_teams(context: &Context, msg: &CommandMessage, data: &TypeMap, title: &str) -> Option<Message> {
+    let draft = &lobby(data, msg.channel_id).draft;
+    let (team_a_name, team_b_name) = draft_team_names(data, draft);
+    let roster = |team: &Vec<User>| -> String {
+        team.iter().map(|user| format!("- @{}\n", &user.name)).collect()
+    };
+    let response = MessageBuilder::new()
+        .push_bold_line(title)
+        .push_bold_line(format!("Team {}:", team_a_name))
+        .push(roster(&draft.team_a))
+        .push_bold_line(format!("Team {}:", team_b_name))
+        .push(roster(&draft.team_b))
+        .build();
+    match msg.channel_id.say(&context.http, &response).await {
+        Ok(m) => Some(m),
+        Err(why) => {
+            eprintln!("Error sending message: {:?}", why);
+            None
+        }
+    }
+}
+
+/// Splits the queue into the two teams with the closest total rating instead of a captain pick & draft.
+/// The highest rated player of each team is made its captain.
+async fn balance_teams(context: &Context, msg: &CommandMessage, data: &mut TypeMap, user_queue: Vec<User>, ratings: Vec<f64>) {
+    let in_team_a = balanced_split(&ratings);
+    let mut team_a: Vec<(User, f64)> = Vec::new();
+    let mut team_b: Vec<(User, f64)> = Vec::new();
+    for (i, user) in user_queue.into_iter().enumerate() {
+        if in_team_a[i] { team_a.push((user, ratings[i])) } else { team_b.push((user, ratings[i])) }
+    }
+    team_a.sort_by(|x, y| y.1.total_cmp(&x.1));
+    team_b.sort_by(|x, y| y.1.total_cmp(&x.1));
+    let roster = |team: &Vec<(User, f64)>| -> String {
+        team.iter().map(|(user, rating)| format!("- @{} `{:.2}`\n", &user.name, rating)).collect()
+    };
+    let average = |team: &Vec<(User, f64)>| -> f64 { team.iter().map(|(_, rating)| rating).sum::<f64>() / team.len() as f64 };
+    let response = MessageBuilder::new()
+        .push_bold_line("Teams have been balanced by rating:")
+        .push_bold_line(format!("Team A (average `{:.2}`):", average(&team_a)))
+        .push(roster(&team_a))
+        .push_bold_line(format!("Team B (average `{:.2}`):", average(&team_b)))
+        .push(roster(&team_b))
+        .build();
+    if let Err(why) = msg.channel_id.say(&context.http, &response).await {
+        eprintln!("Error sending message: {:?}", why);
+    }
+    let draft: &mut Draft = &mut lobby_mut(data, msg.channel_id).draft;
+    draft.captain_a = Some(team_a[0].0.clone());
+    draft.captain_b = Some(team_b[0].0.clone());
+    draft.team_a = team_a.into_iter().map(|(user, _)| user).collect();
+    draft.team_b = team_b.into_iter().map(|(user, _)| user).collect();
+    draft.current_picker = None;
+    teams_complete(context, msg, data).await;
+}
+
+/// What's needed to rate players, collected while the lock is held so the api can be queried once it's released
+struct RatingSources {
+    steam_ids: HashMap<u64, String>,
+    win_loss: HashMap<u64, (u32, u32)>,
+    client: Option<(reqwest::Client, String)>,
+}
+
+fn rating_sources(data: &TypeMap, users: &[User]) -> RatingSources {
+    let steam_id_cache: &HashMap<u64, String> = data.get::<SteamIdCache>().unwrap();
+    let db: &Database = data.get::<Db>().unwrap();
+    let win_loss = db.win_loss().unwrap_or_else(|why| {
+        eprintln!("Error reading match history: {:?}", why);
+        HashMap::new()
+    });
+    RatingSources {
+        steam_ids: users.iter()
+            .filter_map(|u| steam_id_cache.get(u.id.as_u64()).map(|s| (*u.id.as_u64(), String::from(s))))
+            .collect(),
+        win_loss,
+        client: scrimbot_api_client(data.get::<Config>().unwrap()),
+    }
+}
+
+/// Ratings for the given players, from scrimbot-api when it is configured and has stats for the player.
+/// Everyone else gets a rating from their win rate in the local match history, 1.00 being average.
+async fn player_ratings(users: &[User], sources: RatingSources) -> Vec<f64> {
+    let RatingSources { steam_ids, win_loss, client } = sources;
+    let mut ratings = Vec::new();
+    for user in users {
+        let mut rating = None;
+        if let (Some((client, url)), Some(steam_id)) = (&client, steam_ids.get(user.id.as_u64())) {
+            rating = fetch_rating(client, url, steam_id).await;
+        }
+        let local_rating = || {
+            let (wins, played) = win_loss.get(user.id.as_u64()).copied().unwrap_or((0, 0));
+            win_rate_rating(wins, played)
+        };
+        ratings.push(rating.unwrap_or_else(local_rating));
+    }
+    ratings
+}
+
+/// A rating from the local match history, a win rate of (wins + 1) / (played + 2) keeps new players at exactly 1.00
+fn win_rate_rating(wins: u32, played: u32) -> f64 {
+    (wins as f64 + 1.0) / (played as f64 + 2.0) * 2.0
+}
+
+/// Which players go on team A, out of every split into two equal teams the one with the smallest rating difference
+fn balanced_split(ratings: &[f64]) -> Vec<bool> {
+    let player_count = ratings.len();
+    let total: f64 = ratings.iter().sum();
+    let mut best_split = 0u32;
+    let mut best_difference = f64::MAX;
+    // player 0 is always on team A, the mirrored splits would just swap the team names
+    for split in (1u32..1 << player_count).step_by(2) {
+        if split.count_ones() as usize != player_count / 2 { continue; }
+        let team_a_total: f64 = (0..player_count).filter(|i| split & (1 << i) != 0).map(|i| ratings[i]).sum();
+        let difference = (total - team_a_total * 2.0).abs();
+        if difference < best_difference {
+            best_difference = difference;
+            best_split = split;
+        }
+    }
+    (0..player_count).map(|i| best_split & (1 << i) != 0).collect()
+}
+
+/// A client authenticated against scrimbot-api and its url, if the api is configured
+fn scrimbot_api_client(config: &Config) -> Option<(reqwest::Client, String)> {
+    let api_config = &config.scrimbot_api_config;
+    let url = api_config.scrimbot_api_url.clone()?;
+    let auth = format!("{}:{}", api_config.scrimbot_api_user.as_ref()?, api_config.scrimbot_api_password.as_ref()?);
+    let mut headers = header::HeaderMap::new();
+    headers.insert("Authorization", format!("Basic {}", base64::encode(auth)).parse().ok()?);
+    let client = reqwest::Client::builder().default_headers(headers).timeout(SCRIMBOT_API_TIMEOUT).build().ok()?;
+    Some((client, url))
+}
+
+async fn fetch_rating(client: &reqwest::Client, scrimbot_api_url: &str, steam_id: &str) -> Option<f64> {
+    let mut steam_id = String::from(steam_id);
+    steam_id.replace_range(6..7, "1");
     let resp = client
-        .put(&update_map_url)
-        .form(&[("csgo_settings.mapgroup_start_map", &selected_map)])
-        .basic_auth(&dathost_username, dathost_password)
+        .get(format!("{}/api/stats", scrimbot_api_url))
+        .query(&[("steamid", &steam_id)])
         .send()
         .await
-        .unwrap();
-    println!("Change map response - {:#?}", resp.status());
-    let mut bot_state: &mut StateContainer = data.get_mut::<BotState>().unwrap();
-    bot_state.state = State::CaptainPick;
-    let draft: &mut Draft = &mut data.get_mut::<Draft>().unwrap();
-    draft.captain_a = None;
-    draft.captain_b = None;
-    draft.team_a = Vec::new();
-    draft.team_b = Vec::new();
-    send_simple_msg(&context, &msg, "Starting captain pick phase. Two users type `.captain` to start picking teams.").await;
+        .ok()?;
+    if resp.status() != 200 {
+        eprintln!("HTTP error on /api/stats with following params: steamid: {}", &steam_id);
+        return None;
+    }
+    let stats: Vec<Stats> = resp.json().await.ok()?;
+    // a missing rating may come back as NaN, which can't be balanced against
+    stats.first().map(|s| s.rating).filter(|rating| rating.is_finite())
 }
 
 
-pub(crate) async fn handle_captain(context: Context, msg: Message) {
+pub(crate) async fn handle_captain(context: Context, msg: CommandMessage) {
+    if !lobby_check(&context, &msg).await { return; }
     let mut data = context.data.write().await;
-    let bot_state: &mut StateContainer = &mut data.get_mut::<BotState>().unwrap();
+    let bot_state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
     if bot_state.state != State::CaptainPick {
         send_simple_tagged_msg(&context, &msg, " command ignored, not in the captain pick phase", &msg.author).await;
         return;
     }
-    let user_queue: &Vec<User> = data.get::<UserQueue>().unwrap();
+    let user_queue: &Vec<User> = &lobby(&data, msg.channel_id).user_queue;
     if !user_queue.contains(&msg.author) {
         send_simple_tagged_msg(&context, &msg, " command ignored, you are not in the queue", &msg.author).await;
         return;
     }
-    let draft: &mut Draft = &mut data.get_mut::<Draft>().unwrap();
+    let pick_order = pick_order(data.get::<Config>().unwrap());
+    let team_size = team_size(data.get::<Config>().unwrap());
+    let draft: &mut Draft = &mut lobby_mut(&mut data, msg.channel_id).draft;
     if draft.captain_a != None && &msg.author == draft.captain_a.as_ref().unwrap() {
         send_simple_tagged_msg(&context, &msg, " you're already a captain!", &msg.author).await;
         return;
@@ -427,31 +1058,40 @@ pub(crate) async fn handle_captain(context: Context, msg: Message) {
         draft.team_b.push(draft.captain_b.clone().unwrap());
         send_simple_tagged_msg(&context, &msg, " is set as the first pick captain (Team A)", &draft.captain_a.clone().unwrap()).await;
         send_simple_tagged_msg(&context, &msg, " is set as the second captain (Team B)", &draft.captain_b.clone().unwrap()).await;
-        draft.current_picker = draft.captain_a.clone();
+        draft.pick_index = 0;
+        draft.current_picker = next_picker(draft, &pick_order, team_size);
+        if team_size == 1 {
+            // the captains are the whole teams in a 1v1, there is nobody left to draft
+            teams_complete(&context, &msg, &mut data).await;
+            save_state(&data);
+            return;
+        }
+        let draft: &Draft = &lobby(&data, msg.channel_id).draft;
         let response = MessageBuilder::new()
             .push("Captain pick has concluded. Starting draft phase. ")
             .mention(&draft.current_picker.clone().unwrap())
-            .push(" gets first `.pick @<user>`")
+            .push(" gets first pick, use the buttons below or `.pick @<user>`")
             .build();
         if let Err(why) = msg.channel_id.say(&context.http, &response).await {
             eprintln!("Error sending message: {:?}", why);
         }
-        let bot_state: &mut StateContainer = &mut data.get_mut::<BotState>().unwrap();
+        let bot_state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
         bot_state.state = State::Draft;
-        let user_queue: &Vec<User> = &mut data.get::<UserQueue>().unwrap();
-        let draft: &Draft = &mut data.get::<Draft>().unwrap();
-        let teamname_cache = data.get::<TeamNameCache>().unwrap();
-        let team_a_name = teamname_cache.get(draft.captain_a.as_ref().unwrap().id.as_u64())
-            .unwrap_or(&draft.captain_a.as_ref().unwrap().name);
-        let team_b_name = teamname_cache.get(draft.captain_b.as_ref().unwrap().id.as_u64())
-            .unwrap_or(&draft.captain_b.as_ref().unwrap().name);
-        list_unpicked(&user_queue, &draft, &context, &msg, team_a_name, team_b_name).await;
+        lobby_mut(&mut data, msg.channel_id).draft.draft_message_id = None;
+        show_draft(&context, msg.channel_id, &mut data).await;
     }
+    save_state(&data);
 }
 
-pub(crate) async fn handle_pick(context: Context, msg: Message) {
+pub(crate) async fn handle_pick(context: Context, msg: CommandMessage) {
+    if !lobby_check(&context, &msg).await { return; }
     let mut data = context.data.write().await;
-    let bot_state: &mut StateContainer = &mut data.get_mut::<BotState>().unwrap();
+    if lobby(&data, msg.channel_id).state.state == State::MapVeto {
+        let map = map_arg(&msg.content);
+        veto_step(&context, &msg, &mut data, map, true).await;
+        return;
+    }
+    let bot_state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
     if bot_state.state != State::Draft {
         send_simple_tagged_msg(&context, &msg, " it is not currently the draft phase", &msg.author).await;
         return;
@@ -461,12 +1101,12 @@ pub(crate) async fn handle_pick(context: Context, msg: Message) {
         return;
     }
     let picked = msg.mentions[0].clone();
-    let user_queue: &Vec<User> = &data.get::<UserQueue>().unwrap().to_vec();
+    let user_queue: &Vec<User> = &lobby(&data, msg.channel_id).user_queue.to_vec();
     if !user_queue.contains(&picked) {
         send_simple_tagged_msg(&context, &msg, " this user is not in the queue", &msg.author).await;
         return;
     }
-    let draft = data.get::<Draft>().unwrap();
+    let draft = &lobby(&data, msg.channel_id).draft;
     let current_picker = draft.current_picker.clone().unwrap();
     if msg.author != *draft.captain_a.as_ref().unwrap() && msg.author != *draft.captain_b.as_ref().unwrap() {
         send_simple_tagged_msg(&context, &msg, " you are not a captain", &msg.author).await;
@@ -485,115 +1125,632 @@ pub(crate) async fn handle_pick(context: Context, msg: Message) {
         return;
     }
 
-    let teamname_cache = data.get::<TeamNameCache>().unwrap();
-    let team_a_name = String::from(teamname_cache.get(draft.captain_a.as_ref().unwrap().id.as_u64())
-        .unwrap_or(&draft.captain_a.as_ref().unwrap().name));
-    let team_b_name = String::from(teamname_cache.get(draft.captain_b.as_ref().unwrap().id.as_u64())
-        .unwrap_or(&draft.captain_b.as_ref().unwrap().name));
-    let draft: &mut Draft = &mut data.get_mut::<Draft>().unwrap();
+    let team_size = team_size(data.get::<Config>().unwrap());
+    let pick_order = pick_order(data.get::<Config>().unwrap());
+    let (team_a_name, team_b_name) = draft_team_names(&data, draft);
+    let draft: &mut Draft = &mut lobby_mut(&mut data, msg.channel_id).draft;
     if draft.captain_a.as_ref().unwrap() == &current_picker {
         send_simple_tagged_msg(&context, &msg, &format!(" has been added to Team {}", team_a_name), &picked).await;
         draft.team_a.push(picked);
-        draft.current_picker = draft.captain_b.clone();
-        list_unpicked(&user_queue, &draft, &context, &msg, &team_a_name, &team_b_name).await;
     } else {
         send_simple_tagged_msg(&context, &msg, &format!(" has been added to Team {}", team_b_name), &picked).await;
         draft.team_b.push(picked);
-        draft.current_picker = draft.captain_a.clone();
-        list_unpicked(&user_queue, &draft, &context, &msg, &team_a_name, &team_b_name).await;
-    }
-    if draft.team_a.len() == 5 && draft.team_b.len() == 5 {
-        let captain_b = draft.captain_b.clone().unwrap();
-        let bot_state: &mut StateContainer = &mut data.get_mut::<BotState>().unwrap();
-        bot_state.state = State::SidePick;
-        let sidepick_msg = send_simple_tagged_msg(&context, &msg, " type `.ct` or `.t` to pick a starting side.", &captain_b).await;
-        let config: &mut Config = &mut data.get_mut::<Config>().unwrap();
-        if let Some(msg) = sidepick_msg {
-            if let Some(emote_ct_id) = &config.discord.emote_ct_id {
-                if let Some(emote_ct_name) = &config.discord.emote_ct_name {
-                    if let Err(why) = msg.react(&context.http, ReactionType::Custom { animated: false, id: EmojiId(*emote_ct_id), name: Some(String::from(emote_ct_name)) }).await {
-                        eprintln!("Error reacting with custom emoji: {:?}", why)
-                    };
-                }
+    }
+    draft.pick_index += 1;
+    draft.current_picker = next_picker(draft, &pick_order, team_size);
+    let draft_complete = draft.team_a.len() == team_size && draft.team_b.len() == team_size;
+    show_draft(&context, msg.channel_id, &mut data).await;
+    if draft_complete {
+        teams_complete(&context, &msg, &mut data).await;
+    }
+    save_state(&data);
+}
+
+/// Moves on once both teams are full, to the map veto if `.start veto` was used and otherwise to the side pick
+async fn teams_complete(context: &Context, msg: &CommandMessage, data: &mut TypeMap) {
+    if lobby(data, msg.channel_id).veto.is_none() {
+        start_side_pick(context, msg, data).await;
+        return;
+    }
+    let bot_state: &mut StateContainer = &mut lobby_mut(data, msg.channel_id).state;
+    bot_state.state = State::MapVeto;
+    send_simple_msg(context, msg, "Teams are set. Starting the map veto, captains take turns using the buttons below or `.ban <map>` / `.pick <map>`.").await;
+    show_veto(context, msg.channel_id, data).await;
+}
+
+pub(crate) async fn handle_ban(context: Context, msg: CommandMessage) {
+    if !lobby_check(&context, &msg).await { return; }
+    let mut data = context.data.write().await;
+    if lobby(&data, msg.channel_id).state.state != State::MapVeto {
+        send_simple_tagged_msg(&context, &msg, " it is not currently the map veto phase", &msg.author).await;
+        return;
+    }
+    let map = map_arg(&msg.content);
+    veto_step(&context, &msg, &mut data, map, false).await;
+}
+
+/// Whether the veto's current step is a pick, in a BO3 the two picks follow the first round of bans
+fn is_pick_step(veto: &Veto) -> bool {
+    if !veto.best_of_three { return false; }
+    let first_pick = 2.min(veto.map_count - 3);
+    veto.step == first_pick || veto.step == first_pick + 1
+}
+
+/// The captain whose turn it is in the veto, captain A goes first
+fn veto_captain(draft: &Draft, veto: &Veto) -> Option<User> {
+    if veto.step.is_multiple_of(2) { draft.captain_a.clone() } else { draft.captain_b.clone() }
+}
+
+/// Bans or picks a map for the captain whose turn it is, finishing the veto once a single map remains
+async fn veto_step(context: &Context, msg: &CommandMessage, data: &mut TypeMap, map: Option<String>, pick: bool) {
+    let lobby_ref = lobby(data, msg.channel_id);
+    let veto = lobby_ref.veto.as_ref().unwrap();
+    if veto_captain(&lobby_ref.draft, veto).as_ref() != Some(&msg.author) {
+        send_simple_tagged_msg(context, msg, " it is not your turn in the map veto", &msg.author).await;
+        return;
+    }
+    if is_pick_step(veto) != pick {
+        let text = if pick { " it is your turn to ban a map, use `.ban <map>`" } else { " it is your turn to pick a map, use `.pick <map>`" };
+        send_simple_tagged_msg(context, msg, text, &msg.author).await;
+        return;
+    }
+    let map = match map.and_then(|m| veto.remaining.iter().find(|r| r.eq_ignore_ascii_case(&m)).cloned()) {
+        Some(map) => map,
+        None => {
+            send_simple_tagged_msg(context, msg, " please provide one of the remaining maps i.e. `.ban de_dust2`", &msg.author).await;
+            return;
+        }
+    };
+    let veto = lobby_mut(data, msg.channel_id).veto.as_mut().unwrap();
+    veto.remaining.retain(|m| m != &map);
+    if pick {
+        veto.picked.push(String::from(&map));
+        veto.picked_by.push(*msg.author.id.as_u64());
+    }
+    veto.step += 1;
+    let finished = veto.remaining.len() == 1;
+    send_simple_tagged_msg(context, msg, &format!(" {} `{}`", if pick { "picked" } else { "banned" }, &map), &msg.author).await;
+    if finished {
+        let veto = lobby_mut(data, msg.channel_id).veto.as_mut().unwrap();
+        let decider = veto.remaining.pop().unwrap();
+        veto.picked.push(decider);
+        veto.picked_by.push(*msg.author.id.as_u64());
+        let maps = veto.picked.clone();
+        if veto.best_of_three {
+            let picked_by = veto.picked_by.clone();
+            let draft = &lobby(data, msg.channel_id).draft;
+            let captain_a_id = *draft.captain_a.as_ref().unwrap().id.as_u64();
+            let captain_b_id = *draft.captain_b.as_ref().unwrap().id.as_u64();
+            let side_pickers = picked_by.iter()
+                .map(|id| if *id == captain_a_id { captain_b_id } else { captain_a_id })
+                .collect();
+            lobby_mut(data, msg.channel_id).series = Some(Series { maps: maps.clone(), side_pickers, map_index: 0, score_a: 0, score_b: 0, match_id: None });
+        }
+        lobby_mut(data, msg.channel_id).draft.map = String::from(&maps[0]);
+        show_veto(context, msg.channel_id, data).await;
+        let maps_text: Vec<String> = maps.iter().map(|m| format!("`{}`", m)).collect();
+        send_simple_msg(context, msg, &format!("Map veto has concluded. {} will be played", maps_text.join(", "))).await;
+        start_side_pick(context, msg, data).await;
+    } else {
+        show_veto(context, msg.channel_id, data).await;
+    }
+    save_state(data);
+}
+
+/// Posts the veto message, or edits it in place once it has been posted.
+/// Shows the bans & picks so far and a button per remaining map for the captain whose turn it is.
+async fn show_veto(context: &Context, channel_id: ChannelId, data: &mut TypeMap) {
+    let lobby = lobby(data, channel_id);
+    let veto = lobby.veto.as_ref().unwrap();
+    let finished = veto.remaining.len() <= 1;
+    let pick = is_pick_step(veto);
+    let mut content = MessageBuilder::new();
+    content.push_bold_line(format!("Map veto ({})", if veto.best_of_three { "BO3" } else { "BO1" }));
+    if !veto.picked.is_empty() {
+        let picked: Vec<String> = veto.picked.iter().map(|m| format!("`{}`", m)).collect();
+        content.push_line(format!("Maps: {}", picked.join(", ")));
+    }
+    match (veto_captain(&lobby.draft, veto), finished) {
+        (Some(captain), false) => content.mention(&captain).push(if pick { " picks the next map" } else { " bans the next map" }),
+        _ => content.push_bold("Map veto is complete"),
+    };
+    let content = content.build();
+    let remaining = if finished { Vec::new() } else { veto.remaining.clone() };
+    let rows: Vec<CreateActionRow> = remaining.chunks(5)
+        .map(|chunk| {
+            let mut row = CreateActionRow::default();
+            for map in chunk {
+                row.create_button(|b| b
+                    .style(if pick { ButtonStyle::Success } else { ButtonStyle::Danger })
+                    .label(map)
+                    .custom_id(format!("{}{}", VETO_PREFIX, map)));
             }
-            if let Some(emote_t_id) = &config.discord.emote_t_id {
-                if let Some(emote_t_name) = &config.discord.emote_t_name {
-                    if let Err(why) = msg.react(&context.http, ReactionType::Custom { animated: false, id: EmojiId(*emote_t_id), name: Some(String::from(emote_t_name)) }).await {
-                        eprintln!("Error reacting with custom emoji: {:?}", why)
-                    };
-                }
+            row
+        })
+        .take(5)
+        .collect();
+    if let Some(message_id) = veto.message_id {
+        match channel_id.edit_message(&context.http, message_id, |m| m.content(&content).components(|c| c.set_action_rows(rows.clone()))).await {
+            Ok(_) => return,
+            Err(why) => eprintln!("Error editing veto message, posting a new one: {:?}", why),
+        }
+    }
+    match channel_id.send_message(&context.http, |m| m.content(&content).components(|c| c.set_action_rows(rows.clone()))).await {
+        Ok(message) => lobby_mut(data, channel_id).veto.as_mut().unwrap().message_id = Some(*message.id.as_u64()),
+        Err(why) => eprintln!("Error sending message: {:?}", why),
+    }
+}
+
+/// Moves on to the side pick phase once both teams are full, captain B picks the starting side unless
+/// it's a series map picked by captain B
+async fn start_side_pick(context: &Context, msg: &CommandMessage, data: &mut TypeMap) {
+    let side_picker = side_picker(lobby(data, msg.channel_id));
+    let bot_state: &mut StateContainer = &mut lobby_mut(data, msg.channel_id).state;
+    bot_state.state = State::SidePick;
+    let sidepick_msg = send_simple_tagged_msg(context, msg, " type `.ct` or `.t` to pick a starting side.", &side_picker).await;
+    let config: &Config = data.get::<Config>().unwrap();
+    if let Some(msg) = sidepick_msg {
+        if let Some(emote_ct_id) = &config.discord.emote_ct_id {
+            if let Some(emote_ct_name) = &config.discord.emote_ct_name {
+                if let Err(why) = msg.react(&context.http, ReactionType::Custom { animated: false, id: EmojiId(*emote_ct_id), name: Some(String::from(emote_ct_name)) }).await {
+                    eprintln!("Error reacting with custom emoji: {:?}", why)
+                };
+            }
+        }
+        if let Some(emote_t_id) = &config.discord.emote_t_id {
+            if let Some(emote_t_name) = &config.discord.emote_t_name {
+                if let Err(why) = msg.react(&context.http, ReactionType::Custom { animated: false, id: EmojiId(*emote_t_id), name: Some(String::from(emote_t_name)) }).await {
+                    eprintln!("Error reacting with custom emoji: {:?}", why)
+                };
             }
         }
     }
 }
 
-pub(crate) async fn list_unpicked(user_queue: &Vec<User>, draft: &Draft, context: &Context, msg: &Message, team_a_name: &String, team_b_name: &String) {
-    let remaining_users: String = user_queue
+pub(crate) async fn handle_button(context: Context, interaction: MessageComponentInteraction) {
+    if interaction.data.custom_id.starts_with(DRAFT_PICK_PREFIX) {
+        handle_draft_button(context, interaction).await;
+    } else if interaction.data.custom_id.starts_with(VETO_PREFIX) {
+        handle_veto_button(context, interaction).await;
+    } else if interaction.data.custom_id.starts_with(READY_PREFIX) || interaction.data.custom_id.starts_with(UNREADY_PREFIX) {
+        handle_ready_button(context, interaction).await;
+    }
+}
+
+/// Bans or picks the pressed map for the captain whose turn it is in the veto
+async fn handle_veto_button(context: Context, interaction: MessageComponentInteraction) {
+    let map = String::from(interaction.data.custom_id.trim_start_matches(VETO_PREFIX));
+    let pick = {
+        let data = context.data.read().await;
+        let lobby = match data.get::<Lobbies>().unwrap().get(interaction.channel_id.as_u64()) {
+            Some(lobby) => lobby,
+            None => return,
+        };
+        let veto = match (&lobby.veto, &lobby.state.state) {
+            (Some(veto), State::MapVeto) => veto,
+            _ => return,
+        };
+        if veto_captain(&lobby.draft, veto).as_ref() != Some(&interaction.user) {
+            send_ephemeral_response(&context, &interaction, "Only the captain whose turn it is can ban or pick a map.").await;
+            return;
+        }
+        is_pick_step(veto)
+    };
+    if let Err(why) = interaction.create_interaction_response(&context.http, |response| {
+        response.kind(InteractionResponseType::DeferredUpdateMessage)
+    }).await {
+        eprintln!("Error responding to veto button: {:?}", why);
+    }
+    let msg = CommandMessage {
+        author: interaction.user.clone(),
+        channel_id: interaction.channel_id,
+        guild_id: interaction.guild_id,
+        content: format!(".{} {}", if pick { "pick" } else { "ban" }, &map),
+        mentions: Vec::new(),
+    };
+    let mut data = context.data.write().await;
+    veto_step(&context, &msg, &mut data, Some(map), pick).await;
+}
+
+/// Lets the current picker draft a player by pressing their button on the draft message
+async fn handle_draft_button(context: Context, interaction: MessageComponentInteraction) {
+    let picked_id = match interaction.data.custom_id.strip_prefix(DRAFT_PICK_PREFIX).and_then(|id| id.parse::<u64>().ok()) {
+        Some(picked_id) => picked_id,
+        None => return,
+    };
+    let picked = {
+        let data = context.data.read().await;
+        let lobby = match data.get::<Lobbies>().unwrap().get(interaction.channel_id.as_u64()) {
+            Some(lobby) => lobby,
+            None => return,
+        };
+        if lobby.state.state != State::Draft || lobby.draft.current_picker.as_ref() != Some(&interaction.user) {
+            send_ephemeral_response(&context, &interaction, "Only the captain whose turn it is can pick a player.").await;
+            return;
+        }
+        lobby.user_queue.iter().find(|u| *u.id.as_u64() == picked_id).cloned()
+    };
+    let picked = match picked {
+        Some(picked) => picked,
+        None => {
+            send_ephemeral_response(&context, &interaction, "This player is no longer in the queue.").await;
+            return;
+        }
+    };
+    // the draft message itself is edited by the pick, so just acknowledge the button press
+    if let Err(why) = interaction.create_interaction_response(&context.http, |response| {
+        response.kind(InteractionResponseType::DeferredUpdateMessage)
+    }).await {
+        eprintln!("Error responding to draft button: {:?}", why);
+    }
+    let msg = CommandMessage {
+        author: interaction.user.clone(),
+        channel_id: interaction.channel_id,
+        guild_id: interaction.guild_id,
+        content: format!(".pick <@{}>", picked.id),
+        mentions: vec![picked],
+    };
+    handle_pick(context, msg).await;
+}
+
+/// Posts the draft message, or edits it in place once it has been posted.
+/// Shows both teams, whose pick it is and a button per player still available.
+async fn show_draft(context: &Context, channel_id: ChannelId, data: &mut TypeMap) {
+    let lobby = lobby(data, channel_id);
+    let draft = &lobby.draft;
+    let (team_a_name, team_b_name) = draft_team_names(data, draft);
+    let roster = |team: &Vec<User>| -> String {
+        team.iter().map(|user| format!("- @{}\n", &user.name)).collect()
+    };
+    let mut content = MessageBuilder::new();
+    content
+        .push_bold_line(format!("Team {}:", team_a_name))
+        .push(roster(&draft.team_a))
+        .push_bold_line(format!("Team {}:", team_b_name))
+        .push(roster(&draft.team_b));
+    let remaining: Vec<&User> = lobby.user_queue
         .iter()
         .filter(|user| !draft.team_a.contains(user) && !draft.team_b.contains(user))
-        .map(|user| format!("- @{}\n", &user.name))
         .collect();
-    let team_a: String = draft.team_a
-        .iter()
-        .map(|user| format!("- @{}\n", &user.name))
-        .collect();
-    let team_b: String = draft.team_b
-        .iter()
-        .map(|user| format!("- @{}\n", &user.name))
+    let config: &Config = data.get::<Config>().unwrap();
+    match (&draft.current_picker, remaining.is_empty()) {
+        (Some(picker), false) => {
+            let picks_left = picks_left_in_turn(draft, &pick_order(config), team_size(config));
+            content.push("Next pick: ").mention(picker)
+                .push(format!(" ({} pick{} left this turn)", picks_left, if picks_left == 1 { "" } else { "s" }))
+        }
+        _ => content.push_bold("Draft is complete"),
+    };
+    let content = content.build();
+    // at most 5 buttons per row and 5 rows per message
+    let rows: Vec<CreateActionRow> = remaining.chunks(5)
+        .map(|chunk| {
+            let mut row = CreateActionRow::default();
+            for user in chunk {
+                row.create_button(|b| b
+                    .style(ButtonStyle::Secondary)
+                    .label(&user.name)
+                    .custom_id(format!("{}{}", DRAFT_PICK_PREFIX, user.id)));
+            }
+            row
+        })
+        .take(5)
         .collect();
-    let response = MessageBuilder::new()
-        .push_bold_line(format!("Team {}:", team_a_name))
-        .push_line(team_a)
-        .push_bold_line(format!("Team {}:", team_b_name))
-        .push_line(team_b)
-        .push_bold_line("Remaining players: ")
-        .push_line(remaining_users)
-        .build();
+    if let Some(message_id) = draft.draft_message_id {
+        match channel_id.edit_message(&context.http, message_id, |m| m.content(&content).components(|c| c.set_action_rows(rows.clone()))).await {
+            Ok(_) => return,
+            Err(why) => eprintln!("Error editing draft message, posting a new one: {:?}", why),
+        }
+    }
+    match channel_id.send_message(&context.http, |m| m.content(&content).components(|c| c.set_action_rows(rows.clone()))).await {
+        Ok(message) => lobby_mut(data, channel_id).draft.draft_message_id = Some(*message.id.as_u64()),
+        Err(why) => eprintln!("Error sending message: {:?}", why),
+    }
+}
 
-    if let Err(why) = msg.channel_id.say(&context.http, &response).await {
+/// The order captains pick in as a string of `A`s and `B`s, repeated until both teams are full
+pub(crate) fn pick_order(config: &Config) -> String {
+    match config.pick_order.as_deref() {
+        None | Some("alternate") => String::from("AB"),
+        Some("snake") => String::from("ABBA"),
+        Some(custom) => custom.to_uppercase(),
+    }
+}
+
+/// Which team makes pick number `pick_index`, a captain whose team is already full is skipped
+fn picking_team(pick_order: &str, pick_index: usize, team_a_len: usize, team_b_len: usize, team_size: usize) -> char {
+    match pick_order.chars().nth(pick_index % pick_order.len()).unwrap_or('A') {
+        'A' if team_a_len >= team_size => 'B',
+        'B' if team_b_len >= team_size => 'A',
+        team => team,
+    }
+}
+
+/// The captain who makes the draft's next pick, `None` once both teams are full
+fn next_picker(draft: &Draft, pick_order: &str, team_size: usize) -> Option<User> {
+    if draft.team_a.len() >= team_size && draft.team_b.len() >= team_size {
+        return None;
+    }
+    match picking_team(pick_order, draft.pick_index, draft.team_a.len(), draft.team_b.len(), team_size) {
+        'A' => draft.captain_a.clone(),
+        _ => draft.captain_b.clone(),
+    }
+}
+
+/// How many picks in a row the current picker gets, i.e. 2 for captain B's turns in a snake draft
+fn picks_left_in_turn(draft: &Draft, pick_order: &str, team_size: usize) -> usize {
+    let (mut team_a_len, mut team_b_len) = (draft.team_a.len(), draft.team_b.len());
+    let team = picking_team(pick_order, draft.pick_index, team_a_len, team_b_len, team_size);
+    let mut picks = 0;
+    while team_a_len + team_b_len < team_size * 2
+        && picking_team(pick_order, draft.pick_index + picks, team_a_len, team_b_len, team_size) == team {
+        if team == 'A' { team_a_len += 1 } else { team_b_len += 1 }
+        picks += 1;
+    }
+    picks
+}
+
+/// The names shown during the draft, a captain's custom team name or their discord name
+fn draft_team_names(data: &TypeMap, draft: &Draft) -> (String, String) {
+    let teamname_cache = data.get::<TeamNameCache>().unwrap();
+    let team_name = |captain: &Option<User>| -> String {
+        let captain = captain.as_ref().unwrap();
+        String::from(teamname_cache.get(captain.id.as_u64()).unwrap_or(&captain.name))
+    };
+    (team_name(&draft.captain_a), team_name(&draft.captain_b))
+}
+
+async fn send_ephemeral_response(context: &Context, interaction: &MessageComponentInteraction, text: &str) {
+    if let Err(why) = interaction.create_interaction_response(&context.http, |response| {
+        response
+            .kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|data| data
+                .content(text)
+                .flags(InteractionApplicationCommandCallbackDataFlags::EPHEMERAL))
+    }).await {
         eprintln!("Error sending message: {:?}", why);
     }
 }
 
-pub(crate) async fn handle_ct_option(context: Context, msg: Message) {
+pub(crate) async fn handle_ct_option(context: Context, msg: CommandMessage) {
+    handle_side_option(context, msg, "ct").await;
+}
+
+pub(crate) async fn handle_t_option(context: Context, msg: CommandMessage) {
+    handle_side_option(context, msg, "t").await;
+}
+
+async fn handle_side_option(context: Context, msg: CommandMessage, side: &str) {
+    if !lobby_check(&context, &msg).await { return; }
     let mut data = context.data.write().await;
-    let bot_state: &mut StateContainer = &mut data.get_mut::<BotState>().unwrap();
+    let bot_state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
     if bot_state.state != State::SidePick {
         send_simple_tagged_msg(&context, &msg, " it is not currently the side pick phase", &msg.author).await;
         return;
     }
-    let draft: &mut Draft = &mut data.get_mut::<Draft>().unwrap();
-    if &msg.author != draft.captain_b.as_ref().unwrap() {
-        send_simple_tagged_msg(&context, &msg, " you are not Captain B", &msg.author).await;
+    if msg.author != side_picker(lobby(&data, msg.channel_id)) {
+        send_simple_tagged_msg(&context, &msg, " you are not the captain picking the starting side", &msg.author).await;
         return;
     }
-    draft.team_b_start_side = String::from("ct");
-    let bot_state: &mut StateContainer = &mut data.get_mut::<BotState>().unwrap();
+    let draft: &mut Draft = &mut lobby_mut(&mut data, msg.channel_id).draft;
+    let picked_by_b = draft.captain_b.as_ref() == Some(&msg.author);
+    draft.team_b_start_side = match (side, picked_by_b) {
+        ("ct", true) | ("t", false) => String::from("ct"),
+        _ => String::from("t"),
+    };
+    let bot_state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
     bot_state.state = State::Ready;
-    send_simple_msg(&context, &msg, "Setup is completed. Type `.ready` when you are able start playing. This is a final ready check, once all players are `.ready` the server and match will immediately start.").await;
+    lobby_mut(&mut data, msg.channel_id).ready_message_id = None;
+    start_ready_timeout(&context, msg.channel_id, &mut data);
+    save_state(&data);
+    send_simple_msg(&context, &msg, "Setup is completed. Type `.ready` or use the buttons sent to you in a DM when you are able start playing. This is a final ready check, once all players are `.ready` the server and match will immediately start.").await;
+    if let (Some(ready_timeout), Some(_)) = (data.get::<Config>().unwrap().ready_timeout, lobby(&data, msg.channel_id).ready_deadline) {
+        send_simple_msg(&context, &msg, &format!("Players who are not `.ready` within {} seconds will be replaced by players from the waitlist.", ready_timeout)).await;
+    }
+    show_ready_check(&context.http, msg.channel_id, &mut data).await;
+    save_state(&data);
+    let players = lobby(&data, msg.channel_id).user_queue.clone();
+    drop(data);
+    send_ready_dms(&context.http, msg.channel_id, &players).await;
+}
+
+/// DMs each player a Ready / Not ready button pair for the lobby's ready check
+async fn send_ready_dms(http: &Http, channel_id: ChannelId, players: &[User]) {
+    let mut row = CreateActionRow::default();
+    row.create_button(|b| b
+        .style(ButtonStyle::Success)
+        .label("Ready")
+        .custom_id(format!("{}{}", READY_PREFIX, channel_id)));
+    row.create_button(|b| b
+        .style(ButtonStyle::Danger)
+        .label("Not ready")
+        .custom_id(format!("{}{}", UNREADY_PREFIX, channel_id)));
+    let rows = vec![row];
+    for player in players {
+        let content = format!("Your scrim in <#{}> is about to start, are you ready?", channel_id);
+        let result = match player.create_dm_channel(http).await {
+            Ok(channel) => channel.send_message(http, |m| m.content(&content).components(|c| c.set_action_rows(rows.clone()))).await.map(|_| ()),
+            Err(why) => Err(why),
+        };
+        if let Err(why) = result {
+            eprintln!("Error sending ready check dm to {}: {:?}", player.name, why);
+        }
+    }
+}
+
+/// Posts the ready check summary, or edits it in place once it has been posted
+async fn show_ready_check(http: &Http, channel_id: ChannelId, data: &mut TypeMap) {
+    let lobby = lobby(data, channel_id);
+    let players: String = lobby.user_queue.iter()
+        .map(|user| format!("{} @{}\n", if lobby.ready_queue.contains(user) { "✅" } else { "⌛" }, &user.name))
+        .collect();
+    let content = MessageBuilder::new()
+        .push_bold_line(format!("Ready check: {}/{} ready", lobby.ready_queue.len(), lobby.user_queue.len()))
+        .push(players)
+        .build();
+    if let Some(message_id) = lobby.ready_message_id {
+        match channel_id.edit_message(http, message_id, |m| m.content(&content)).await {
+            Ok(_) => return,
+            Err(why) => eprintln!("Error editing ready check message, posting a new one: {:?}", why),
+        }
+    }
+    match channel_id.say(http, &content).await {
+        Ok(message) => lobby_mut(data, channel_id).ready_message_id = Some(*message.id.as_u64()),
+        Err(why) => eprintln!("Error sending message: {:?}", why),
+    }
 }
 
-pub(crate) async fn handle_t_option(context: Context, msg: Message) {
+/// Mirrors a Ready / Not ready button press in a player's DMs into the lobby's ready queue
+async fn handle_ready_button(context: Context, interaction: MessageComponentInteraction) {
+    let custom_id = &interaction.data.custom_id;
+    let (ready, channel_id) = match (custom_id.strip_prefix(READY_PREFIX), custom_id.strip_prefix(UNREADY_PREFIX)) {
+        (Some(channel_id), _) => (true, channel_id),
+        (_, Some(channel_id)) => (false, channel_id),
+        _ => return,
+    };
+    let channel_id = match channel_id.parse::<u64>() {
+        Ok(channel_id) => ChannelId(channel_id),
+        Err(_) => return,
+    };
+    let is_ready = {
+        let data = context.data.read().await;
+        match data.get::<Lobbies>().unwrap().get(channel_id.as_u64()) {
+            Some(lobby) if lobby.state.state == State::Ready && lobby.user_queue.contains(&interaction.user) => lobby.ready_queue.contains(&interaction.user),
+            _ => {
+                send_ephemeral_response(&context, &interaction, "This ready check is over.").await;
+                return;
+            }
+        }
+    };
+    let content = format!("Your scrim in <#{}> is about to start, you are **{}**", channel_id, if ready { "ready" } else { "not ready" });
+    if let Err(why) = interaction.create_interaction_response(&context.http, |response| {
+        response
+            .kind(InteractionResponseType::UpdateMessage)
+            .interaction_response_data(|message| message.content(&content))
+    }).await {
+        eprintln!("Error responding to ready button: {:?}", why);
+    }
+    let msg = CommandMessage {
+        author: interaction.user.clone(),
+        channel_id,
+        guild_id: context.cache.guild_channel(channel_id).await.map(|c| c.guild_id),
+        content: String::from(if ready { ".ready" } else { ".unready" }),
+        mentions: Vec::new(),
+    };
+    match (ready, is_ready) {
+        (true, false) => handle_ready(context, msg).await,
+        (false, true) => handle_unready(context, msg).await,
+        _ => {}
+    }
+}
+
+/// Sets the ready check's deadline and waits for it in the background, when `ready_timeout` is configured
+fn start_ready_timeout(context: &Context, channel_id: ChannelId, data: &mut TypeMap) {
+    let ready_timeout = match data.get::<Config>().unwrap().ready_timeout {
+        Some(ready_timeout) => ready_timeout,
+        None => return,
+    };
+    // the teams of a series stay together, nobody is replaced from the waitlist in between maps
+    if lobby(data, channel_id).series.is_some() { return; }
+    let deadline = Local::now().timestamp() + ready_timeout as i64;
+    lobby_mut(data, channel_id).ready_deadline = Some(deadline);
+    tokio::spawn(ready_timeout_expired(context.clone(), channel_id, deadline));
+}
+
+/// Picks up the ready checks that were running before a restart
+pub(crate) fn resume_ready_timeouts(context: &Context, data: &TypeMap) {
+    for (channel_id, lobby) in data.get::<Lobbies>().unwrap() {
+        if let (State::Ready, Some(deadline)) = (&lobby.state.state, lobby.ready_deadline) {
+            tokio::spawn(ready_timeout_expired(context.clone(), ChannelId(*channel_id), deadline));
+        }
+    }
+}
+
+/// Once the ready deadline has passed, replaces the players who aren't ready with the first players on the waitlist.
+/// A replacement takes over the dropped player's spot in their team, and captaincy if they were a captain.
+/// The ready check then starts over with a new deadline.
+async fn ready_timeout_expired(context: Context, channel_id: ChannelId, deadline: i64) {
+    let wait = (deadline - Local::now().timestamp()).max(0) as u64;
+    task::sleep(Duration::from_secs(wait)).await;
     let mut data = context.data.write().await;
-    let bot_state: &mut StateContainer = &mut data.get_mut::<BotState>().unwrap();
-    if bot_state.state != State::SidePick {
-        send_simple_tagged_msg(&context, &msg, " it is not currently the side pick phase", &msg.author).await;
-        return;
+    let lobby = match data.get_mut::<Lobbies>().unwrap().get_mut(channel_id.as_u64()) {
+        // the ready check is over, or a newer one has started
+        Some(lobby) if lobby.state.state == State::Ready && lobby.ready_deadline == Some(deadline) && lobby.series.is_none() => lobby,
+        _ => return,
+    };
+    let unready: Vec<User> = lobby.user_queue.iter()
+        .filter(|u| !lobby.ready_queue.contains(u))
+        .cloned()
+        .collect();
+    if unready.is_empty() { return; }
+    let mut response = MessageBuilder::new();
+    let mut replacements = Vec::new();
+    for dropped in unready {
+        if lobby.waitlist.is_empty() {
+            response.mention(&dropped).push_line(" is not ready, but there is nobody on the waitlist to replace them.");
+            continue;
+        }
+        let replacement = lobby.waitlist.remove(0);
+        replace_player(lobby, &dropped, &replacement);
+        response.mention(&dropped).push(" didn't ready in time and was replaced by ").mention(&replacement).push_line("");
+        replacements.push(replacement);
     }
-    let draft: &mut Draft = &mut data.get_mut::<Draft>().unwrap();
-    if &msg.author != draft.captain_b.as_ref().unwrap() {
-        send_simple_tagged_msg(&context, &msg, " you are not Captain B", &msg.author).await;
-        return;
+    if replacements.is_empty() {
+        response.push("Players who join the waitlist before the next deadline will take their place.");
+    } else {
+        response.push("Type `.ready` to ready up, the ready check has been restarted.");
+    }
+    // keep checking so players who join the waitlist later can still fill in
+    start_ready_timeout(&context, channel_id, &mut data);
+    save_state(&data);
+    if let Err(why) = channel_id.say(&context.http, response.build()).await {
+        eprintln!("Error sending message: {:?}", why);
+    }
+    if replacements.is_empty() { return; }
+    show_ready_check(&context.http, channel_id, &mut data).await;
+    save_state(&data);
+    drop(data);
+    send_ready_dms(&context.http, channel_id, &replacements).await;
+}
+
+/// Puts `replacement` in `dropped`'s place in the queue and their team, including their captaincy and side picks
+fn replace_player(lobby: &mut Lobby, dropped: &User, replacement: &User) {
+    let swap = |users: &mut Vec<User>| {
+        if let Some(index) = users.iter().position(|u| u == dropped) {
+            users[index] = replacement.clone();
+        }
+    };
+    swap(&mut lobby.user_queue);
+    swap(&mut lobby.draft.team_a);
+    swap(&mut lobby.draft.team_b);
+    lobby.ready_queue.retain(|u| u != dropped);
+    lobby.queue_messages.remove(dropped.id.as_u64());
+    for captain in [&mut lobby.draft.captain_a, &mut lobby.draft.captain_b, &mut lobby.draft.current_picker] {
+        if captain.as_ref() == Some(dropped) {
+            *captain = Some(replacement.clone());
+        }
+    }
+    if let Some(series) = &mut lobby.series {
+        for side_picker in &mut series.side_pickers {
+            if *side_picker == *dropped.id.as_u64() {
+                *side_picker = *replacement.id.as_u64();
+            }
+        }
     }
-    draft.team_b_start_side = String::from("t");
-    let bot_state: &mut StateContainer = &mut data.get_mut::<BotState>().unwrap();
-    bot_state.state = State::Ready;
-    send_simple_msg(&context, &msg, "Setup is completed. Type `.ready` when you are able start playing. This is a final ready check, once all players are `.ready` the server and match will immediately start.").await;
 }
 
-pub(crate) async fn handle_steam_id(context: Context, msg: Message) {
+/// The captain picking the starting side, captain B or for a series map the captain who didn't pick it
+fn side_picker(lobby: &Lobby) -> User {
+    let draft = &lobby.draft;
+    let picker_id = lobby.series.as_ref().and_then(|series| series.side_pickers.get(series.map_index));
+    match picker_id {
+        Some(id) if draft.captain_a.as_ref().map(|c| c.id.as_u64()) == Some(id) => draft.captain_a.clone().unwrap(),
+        _ => draft.captain_b.clone().unwrap(),
+    }
+}
+
+pub(crate) async fn handle_steam_id(context: Context, msg: CommandMessage) {
     let mut data = context.data.write().await;
-    let steam_id_cache: &mut HashMap<u64, String> = &mut data.get_mut::<SteamIdCache>().unwrap();
     let split_content = msg.content.trim().split(' ').take(2).collect::<Vec<_>>();
     if split_content.len() == 1 {
         send_simple_tagged_msg(&context, &msg, " please check the command formatting. There must be a space in between `.steamid` and your steamid. \
@@ -606,8 +1763,14 @@ pub(crate) async fn handle_steam_id(context: Context, msg: Message) {
         send_simple_tagged_msg(&context, &msg, " invalid steamid formatting. Please follow this example: `.steamid STEAM_0:1:12345678`", &msg.author).await;
         return;
     }
+    let db: &Database = data.get::<Db>().unwrap();
+    if let Err(why) = db.set_steam_id(*msg.author.id.as_u64(), &steam_id_str) {
+        eprintln!("Error saving steamid: {:?}", why);
+        send_simple_tagged_msg(&context, &msg, " sorry, something went wrong saving your steamid", &msg.author).await;
+        return;
+    }
+    let steam_id_cache: &mut HashMap<u64, String> = &mut data.get_mut::<SteamIdCache>().unwrap();
     steam_id_cache.insert(*msg.author.id.as_u64(), String::from(&steam_id_str));
-    write_to_file(String::from("steam-ids.json"), serde_json::to_string(steam_id_cache).unwrap()).await;
     let response = MessageBuilder::new()
         .push("Updated steamid for ")
         .mention(&msg.author)
@@ -620,10 +1783,24 @@ pub(crate) async fn handle_steam_id(context: Context, msg: Message) {
     }
 }
 
-pub(crate) async fn handle_map_list(context: Context, msg: Message) {
+pub(crate) async fn handle_map_list(context: Context, msg: CommandMessage) {
     let data = context.data.write().await;
-    let maps: &Vec<String> = data.get::<Maps>().unwrap();
-    let map_str: String = maps.iter().map(|map| format!("- `{}`\n", map)).collect();
+    let maps: &Vec<MapEntry> = data.get::<Maps>().unwrap();
+    let map_str: String = maps.iter()
+        .map(|map| {
+            let mut line = format!("- `{}`", &map.name);
+            if let Some(workshop_id) = map.workshop_id {
+                line.push_str(&format!(" (workshop {})", workshop_id));
+            } else if map.map_name != map.name {
+                line.push_str(&format!(" ({})", &map.map_name));
+            }
+            if !map.enabled {
+                line.push_str(" _disabled_");
+            }
+            line.push('\n');
+            line
+        })
+        .collect();
     let response = MessageBuilder::new()
         .push_line("Current map pool:")
         .push(map_str)
@@ -633,15 +1810,24 @@ pub(crate) async fn handle_map_list(context: Context, msg: Message) {
     }
 }
 
-pub(crate) async fn handle_kick(context: Context, msg: Message) {
+pub(crate) async fn handle_kick(context: Context, msg: CommandMessage) {
     if !admin_check(&context, &msg, true).await { return; }
+    if !lobby_check(&context, &msg).await { return; }
     let mut data = context.data.write().await;
-    let state: &mut StateContainer = data.get_mut::<BotState>().unwrap();
+    let waitlist: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).waitlist;
+    if let Some(user) = msg.mentions.first().filter(|u| waitlist.contains(u)) {
+        waitlist.retain(|u| u != user);
+        save_state(&data);
+        send_simple_tagged_msg(&context, &msg, " has been kicked from the waitlist.", user).await;
+        return;
+    }
+    let state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
     if state.state != State::Queue {
         send_simple_tagged_msg(&context, &msg, " cannot `.kick` the queue after `.start`, use `.cancel` to start over if needed.", &msg.author).await;
         return;
     }
-    let user_queue: &mut Vec<User> = data.get_mut::<UserQueue>().unwrap();
+    let queue_size = team_size(data.get::<Config>().unwrap()) * 2;
+    let user_queue: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).user_queue;
     let user = &msg.mentions[0];
     if !user_queue.contains(&user) {
         let response = MessageBuilder::new()
@@ -658,19 +1844,94 @@ pub(crate) async fn handle_kick(context: Context, msg: Message) {
     let response = MessageBuilder::new()
         .mention(user)
         .push(" has been kicked. Queue size: ")
-        .push(user_queue.len().to_string())
-        .push("/10")
+        .push(format!("{}/{}", user_queue.len(), queue_size))
+        .build();
+    let promoted = promote_waitlist(&mut data, msg.channel_id);
+    save_state(&data);
+    if let Err(why) = msg.channel_id.say(&context.http, &response).await {
+        eprintln!("Error sending message: {:?}", why);
+    }
+    announce_promoted(&context.http, msg.channel_id, &promoted, queue_size, &data).await;
+    let pop = if promoted.is_empty() { None } else { queue_pop(&data, msg.channel_id) };
+    drop(data);
+    if let Some(pop) = pop {
+        queue_popped(&context, msg.channel_id, pop).await;
+    }
+}
+
+/// `.sub @out @in` swaps a player after `.start` without starting over, the new player takes over the old player's
+/// spot in the queue and their team. Captains can only substitute players on their own team.
+pub(crate) async fn handle_sub(context: Context, msg: CommandMessage) {
+    let is_admin = admin_check(&context, &msg, false).await;
+    if !lobby_check(&context, &msg).await { return; }
+    let mut data = context.data.write().await;
+    let state = &lobby(&data, msg.channel_id).state.state;
+    if *state == State::Queue || *state == State::Live {
+        send_simple_tagged_msg(&context, &msg, " `.sub` can only be used during the `.start` process, use `.kick` or `.leave` in the queue", &msg.author).await;
+        return;
+    }
+    // mentions aren't guaranteed to be in the order they were typed in
+    let mention_regex = Regex::new("<@!?(\\d+)>").unwrap();
+    let players: Vec<&User> = mention_regex.captures_iter(&msg.content)
+        .filter_map(|c| c[1].parse::<u64>().ok())
+        .filter_map(|id| msg.mentions.iter().find(|u| *u.id.as_u64() == id))
+        .collect();
+    if players.len() != 2 {
+        send_simple_tagged_msg(&context, &msg, " please mention the player to replace and their substitute i.e. `.sub @out @in`", &msg.author).await;
+        return;
+    }
+    let (out, sub) = (players[0].clone(), players[1].clone());
+    let lobby_ref = lobby(&data, msg.channel_id);
+    if !lobby_ref.user_queue.contains(&out) {
+        send_simple_tagged_msg(&context, &msg, &format!(" {} is not in the queue", out.name), &msg.author).await;
+        return;
+    }
+    if lobby_ref.user_queue.contains(&sub) {
+        send_simple_tagged_msg(&context, &msg, &format!(" {} is already in the queue", sub.name), &msg.author).await;
+        return;
+    }
+    let draft = &lobby_ref.draft;
+    let own_team = (draft.captain_a.as_ref() == Some(&msg.author) && draft.team_a.contains(&out))
+        || (draft.captain_b.as_ref() == Some(&msg.author) && draft.team_b.contains(&out));
+    if !is_admin && !own_team {
+        send_simple_tagged_msg(&context, &msg, " only admins, or captains for players on their own team, can use `.sub`", &msg.author).await;
+        return;
+    }
+    if !data.get::<SteamIdCache>().unwrap().contains_key(sub.id.as_u64()) {
+        send_simple_tagged_msg(&context, &msg, &format!(" {} needs to set their steamID with `.steamid` first", sub.name), &msg.author).await;
+        return;
+    }
+    let lobby = lobby_mut(&mut data, msg.channel_id);
+    replace_player(lobby, &out, &sub);
+    lobby.waitlist.retain(|u| u != &sub);
+    let state = lobby.state.state.clone();
+    save_state(&data);
+    let response = MessageBuilder::new()
+        .mention(&out)
+        .push(" has been substituted by ")
+        .mention(&sub)
         .build();
     if let Err(why) = msg.channel_id.say(&context.http, &response).await {
         eprintln!("Error sending message: {:?}", why);
     }
+    match state {
+        State::Draft => show_draft(&context, msg.channel_id, &mut data).await,
+        State::MapVeto => show_veto(&context, msg.channel_id, &mut data).await,
+        State::Ready => show_ready_check(&context.http, msg.channel_id, &mut data).await,
+        _ => {}
+    }
+    save_state(&data);
+    drop(data);
+    if state == State::Ready {
+        send_ready_dms(&context.http, msg.channel_id, &[sub]).await;
+    }
 }
 
-pub(crate) async fn handle_add_map(context: Context, msg: Message) {
+pub(crate) async fn handle_add_map(context: Context, msg: CommandMessage) {
     if !admin_check(&context, &msg, true).await { return; }
     let mut data = context.data.write().await;
-    let maps: &mut Vec<String> = data.get_mut::<Maps>().unwrap();
-    if maps.len() >= 26 {
+    let maps: &mut Vec<MapEntry> = data.get_mut::<Maps>().unwrap();
+    if maps.len() >= MAX_MAPS {
         let response = MessageBuilder::new()
             .mention(&msg.author)
             .push(" unable to add map, max amount reached.")
@@ -680,8 +1941,15 @@ pub(crate) async fn handle_add_map(context: Context, msg: Message) {
         }
         return;
     }
-    let map_name: String = String::from(msg.content.trim().split(" ").take(2).collect::<Vec<_>>()[1]);
-    if maps.contains(&map_name) {
+    let map = match parse_map_entry(&msg.content) {
+        Ok(map) => map,
+        Err(why) => {
+            send_simple_tagged_msg(&context, &msg, &format!(" {}. Use `.addmap <map name> [workshop id] [\"display name\"]` i.e. \
+            `.addmap de_dust2` or `.addmap de_cache 123456789 \"Cache\"`", why), &msg.author).await;
+            return;
+        }
+    };
+    if maps.iter().any(|m| m.name.eq_ignore_ascii_case(&map.name)) {
         let response = MessageBuilder::new()
             .mention(&msg.author)
             .push(" unable to add map, already exists.")
@@ -691,12 +1959,19 @@ pub(crate) async fn handle_add_map(context: Context, msg: Message) {
         }
         return;
     }
-    maps.push(String::from(&map_name));
-    write_to_file(String::from("maps.json"), serde_json::to_string(maps).unwrap()).await;
+    let db: &Database = data.get::<Db>().unwrap();
+    if let Err(why) = db.add_map(&map) {
+        eprintln!("Error saving map: {:?}", why);
+        send_simple_tagged_msg(&context, &msg, " sorry, something went wrong saving the map", &msg.author).await;
+        return;
+    }
+    let maps: &mut Vec<MapEntry> = data.get_mut::<Maps>().unwrap();
+    maps.push(map.clone());
+    refresh_slash_commands(&context, &msg, &data).await;
     let response = MessageBuilder::new()
         .mention(&msg.author)
         .push(" added map: `")
-        .push(&map_name)
+        .push(&map.name)
         .push("`")
         .build();
     if let Err(why) = msg.channel_id.say(&context.http, &response).await {
@@ -704,24 +1979,79 @@ pub(crate) async fn handle_add_map(context: Context, msg: Message) {
     }
 }
 
-pub(crate) async fn handle_remove_map(context: Context, msg: Message) {
+/// Parses `.addmap <map name> [workshop id] ["display name"]`, the arguments can be in any order
+fn parse_map_entry(content: &str) -> Result<MapEntry, String> {
+    let args = content.trim().split_once(' ').map_or("", |(_, args)| args);
+    let (display_name, args) = match (args.find('"'), args.rfind('"')) {
+        (Some(start), Some(end)) if end > start => (Some(args[start + 1..end].trim()), format!("{} {}", &args[..start], &args[end + 1..])),
+        (None, None) => (None, String::from(args)),
+        _ => return Err(String::from("the display name is missing a closing quote")),
+    };
+    let (workshop_ids, map_names): (Vec<&str>, Vec<&str>) = args.split_whitespace()
+        .partition(|arg| arg.chars().all(|c| c.is_ascii_digit()));
+    if map_names.len() != 1 || workshop_ids.len() > 1 {
+        return Err(String::from("please provide one map name and at most one workshop id"));
+    }
+    let map_name_regex = Regex::new("^[A-Za-z0-9_-]+$").unwrap();
+    if !map_name_regex.is_match(map_names[0]) {
+        return Err(String::from("map names can only contain letters, numbers, `_` and `-`"));
+    }
+    let mut map = MapEntry::new(map_names[0]);
+    if let Some(workshop_id) = workshop_ids.first() {
+        map.workshop_id = Some(workshop_id.parse::<u64>().map_err(|_| String::from("invalid workshop id"))?);
+    }
+    if let Some(display_name) = display_name {
+        if display_name.is_empty() || display_name.len() > 32 || display_name.contains('`') {
+            return Err(String::from("display names must be 1-32 characters without backticks"));
+        }
+        map.name = String::from(display_name);
+    }
+    Ok(map)
+}
+
+/// The map named after the command, i.e. `de_dust2` in `.ban de_dust2`. Display names may contain spaces.
+fn map_arg(content: &str) -> Option<String> {
+    let map = content.trim().split_once(' ')?.1.trim().trim_matches('"');
+    if map.is_empty() { None } else { Some(String::from(map)) }
+}
+
+/// The names of the enabled maps, these are the ones offered in map votes & vetoes (at most `MAX_MAPS`)
+pub(crate) fn map_pool(data: &TypeMap) -> Vec<String> {
+    data.get::<Maps>().unwrap().iter()
+        .filter(|m| m.enabled)
+        .map(|m| String::from(&m.name))
+        .take(MAX_MAPS)
+        .collect()
+}
+
+pub(crate) async fn handle_remove_map(context: Context, msg: CommandMessage) {
     if !admin_check(&context, &msg, true).await { return; }
     let mut data = context.data.write().await;
-    let maps: &mut Vec<String> = data.get_mut::<Maps>().unwrap();
-    let map_name: String = String::from(msg.content.trim().split(" ").take(2).collect::<Vec<_>>()[1]);
-    if !maps.contains(&map_name) {
-        let response = MessageBuilder::new()
-            .mention(&msg.author)
-            .push(" this map doesn't exist in the list.")
-            .build();
-        if let Err(why) = msg.channel_id.say(&context.http, &response).await {
-            eprintln!("Error sending message: {:?}", why);
+    let maps: &mut Vec<MapEntry> = data.get_mut::<Maps>().unwrap();
+    let map_name: String = map_arg(&msg.content).unwrap_or_default();
+    let index = match maps.iter().position(|m| m.name.eq_ignore_ascii_case(&map_name)) {
+        Some(index) => index,
+        None => {
+            let response = MessageBuilder::new()
+                .mention(&msg.author)
+                .push(" this map doesn't exist in the list.")
+                .build();
+            if let Err(why) = msg.channel_id.say(&context.http, &response).await {
+                eprintln!("Error sending message: {:?}", why);
+            }
+            return;
         }
+    };
+    let map_name = String::from(&maps[index].name);
+    let db: &Database = data.get::<Db>().unwrap();
+    if let Err(why) = db.remove_map(&map_name) {
+        eprintln!("Error removing map: {:?}", why);
+        send_simple_tagged_msg(&context, &msg, " sorry, something went wrong removing the map", &msg.author).await;
         return;
     }
-    let index = maps.iter().position(|m| m == &map_name).unwrap();
+    let maps: &mut Vec<MapEntry> = data.get_mut::<Maps>().unwrap();
     maps.remove(index);
-    write_to_file(String::from("maps.json"), serde_json::to_string(maps).unwrap()).await;
+    refresh_slash_commands(&context, &msg, &data).await;
     let response = MessageBuilder::new()
         .mention(&msg.author)
         .push(" removed map: `")
@@ -733,35 +2063,164 @@ pub(crate) async fn handle_remove_map(context: Context, msg: Message) {
     }
 }
 
-pub(crate) async fn handle_unknown(context: Context, msg: Message) {
+/// `.enablemap` & `.disablemap`, a disabled map stays in the pool but isn't offered in map votes & vetoes
+pub(crate) async fn handle_enable_map(context: Context, msg: CommandMessage, enabled: bool) {
+    if !admin_check(&context, &msg, true).await { return; }
+    let mut data = context.data.write().await;
+    let maps: &Vec<MapEntry> = data.get::<Maps>().unwrap();
+    let map_name: String = map_arg(&msg.content).unwrap_or_default();
+    let index = match maps.iter().position(|m| m.name.eq_ignore_ascii_case(&map_name)) {
+        Some(index) => index,
+        None => {
+            send_simple_tagged_msg(&context, &msg, " this map doesn't exist in the list.", &msg.author).await;
+            return;
+        }
+    };
+    let map_name = String::from(&maps[index].name);
+    let db: &Database = data.get::<Db>().unwrap();
+    if let Err(why) = db.set_map_enabled(&map_name, enabled) {
+        eprintln!("Error updating map: {:?}", why);
+        send_simple_tagged_msg(&context, &msg, " sorry, something went wrong updating the map", &msg.author).await;
+        return;
+    }
+    data.get_mut::<Maps>().unwrap()[index].enabled = enabled;
+    refresh_slash_commands(&context, &msg, &data).await;
+    send_simple_tagged_msg(&context, &msg, &format!(" {} map: `{}`", if enabled { "enabled" } else { "disabled" }, &map_name), &msg.author).await;
+}
+
+/// Re-registers the slash commands so the map choices match the map pool
+async fn refresh_slash_commands(context: &Context, msg: &CommandMessage, data: &TypeMap) {
+    if let Some(guild_id) = msg.guild_id {
+        let config: &Config = data.get::<Config>().unwrap();
+        let maps: &Vec<MapEntry> = data.get::<Maps>().unwrap();
+        slash_commands::register(&context.http, config, guild_id, maps).await;
+    }
+}
+
+pub(crate) async fn handle_history(context: Context, msg: CommandMessage) {
+    let data = context.data.write().await;
+    let split_content = msg.content.trim().split(' ').collect::<Vec<_>>();
+    let count: u32 = match split_content.get(1) {
+        Some(arg) => match arg.parse::<u32>() {
+            Ok(count) if (1..=20).contains(&count) => count,
+            _ => {
+                send_simple_tagged_msg(&context, &msg, " please provide a number of matches between 1 and 20 i.e. `.history 5`", &msg.author).await;
+                return;
+            }
+        },
+        None => 5,
+    };
+    let db: &Database = data.get::<Db>().unwrap();
+    let matches = match db.recent_matches(count) {
+        Ok(matches) => matches,
+        Err(why) => {
+            eprintln!("Error reading match history: {:?}", why);
+            send_simple_tagged_msg(&context, &msg, " sorry, something went wrong reading the match history", &msg.author).await;
+            return;
+        }
+    };
+    if matches.is_empty() {
+        send_simple_msg(&context, &msg, "No matches have been played yet.").await;
+        return;
+    }
+    let match_lines: String = matches
+        .iter()
+        .map(|m| format!("`#{}` {} UTC `{}` {} {} {}\n", m.id, &m.started_at, &m.map, &m.team1_name, format_score(m), &m.team2_name))
+        .collect();
     let response = MessageBuilder::new()
-        .push("Unknown command, type `.help` for list of commands.")
+        .push_bold_line("Match history:")
+        .push(match_lines)
+        .push("Use `.match <id>` for details")
         .build();
     if let Err(why) = msg.channel_id.say(&context.http, &response).await {
         eprintln!("Error sending message: {:?}", why);
     }
 }
 
-pub(crate) async fn write_to_file(path: String, content: String) {
-    let mut error_string = String::from("Error writing to ");
-    error_string.push_str(&path);
-    std::fs::write(path, content)
-        .expect(&error_string);
+pub(crate) async fn handle_match(context: Context, msg: CommandMessage) {
+    let data = context.data.write().await;
+    let split_content = msg.content.trim().split(' ').collect::<Vec<_>>();
+    let match_id = match split_content.get(1).map(|arg| arg.trim_start_matches('#').parse::<i64>()) {
+        Some(Ok(match_id)) => match_id,
+        _ => {
+            send_simple_tagged_msg(&context, &msg, " please provide a match id i.e. `.match 12`", &msg.author).await;
+            return;
+        }
+    };
+    let db: &Database = data.get::<Db>().unwrap();
+    let record = match db.find_match(match_id) {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            send_simple_tagged_msg(&context, &msg, &format!(" match `#{}` not found", match_id), &msg.author).await;
+            return;
+        }
+        Err(why) => {
+            eprintln!("Error reading match history: {:?}", why);
+            send_simple_tagged_msg(&context, &msg, " sorry, something went wrong reading the match history", &msg.author).await;
+            return;
+        }
+    };
+    let roster = |team: u8, captain_id: Option<u64>| -> String {
+        record.players
+            .iter()
+            .filter(|p| p.team == team)
+            .map(|p| format!("- @{}{} `{}`\n", &p.name, if Some(p.discord_id) == captain_id { " (captain)" } else { "" }, &p.steam_id))
+            .collect()
+    };
+    let mut response = MessageBuilder::new();
+    response
+        .push_bold_line(format!("Match #{}", record.id))
+        .push_line(format!("Map: `{}`", &record.map))
+        .push_line(format!("Started: {} UTC", &record.started_at));
+    if let Some(finished_at) = &record.finished_at {
+        response.push_line(format!("Finished: {} UTC", finished_at));
+    }
+    if let Some(dathost_match_id) = &record.dathost_match_id {
+        response.push_line(format!("DatHost match id: `{}`", dathost_match_id));
+    }
+    response
+        .push_line(format!("Score: {} {} {}", &record.team1_name, format_score(&record), &record.team2_name))
+        .push_line("")
+        .push_bold_line(format!("{} (started T):", &record.team1_name))
+        .push(roster(1, record.team1_captain_id))
+        .push_bold_line(format!("{} (started CT):", &record.team2_name))
+        .push(roster(2, record.team2_captain_id));
+    if let Err(why) = msg.channel_id.say(&context.http, &response.build()).await {
+        eprintln!("Error sending message: {:?}", why);
+    }
+}
+
+fn format_score(record: &MatchRecord) -> String {
+    match (record.team1_score, record.team2_score) {
+        (Some(team1_score), Some(team2_score)) => format!("**{}** - **{}**", team1_score, team2_score),
+        _ => String::from("vs"),
+    }
+}
+
+pub(crate) async fn handle_unknown(context: Context, msg: CommandMessage) {
+    let response = MessageBuilder::new()
+        .push("Unknown command, type `.help` for list of commands.")
+        .build();
+    if let Err(why) = msg.channel_id.say(&context.http, &response).await {
+        eprintln!("Error sending message: {:?}", why);
+    }
 }
 
-pub(crate) async fn handle_ready(context: Context, msg: Message) {
+pub(crate) async fn handle_ready(context: Context, msg: CommandMessage) {
+    if !lobby_check(&context, &msg).await { return; }
     let mut data = context.data.write().await;
-    let bot_state: &StateContainer = data.get_mut::<BotState>().unwrap();
+    let bot_state: &StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
     if bot_state.state != State::Ready {
         send_simple_tagged_msg(&context, &msg, " command ignored. The draft has not been completed yet", &msg.author).await;
         return;
     }
-    let user_queue: &Vec<User> = &data.get::<UserQueue>().unwrap();
+    let user_queue: &Vec<User> = &lobby(&data, msg.channel_id).user_queue;
     if !user_queue.contains(&msg.author) {
         send_simple_tagged_msg(&context, &msg, " you are not in the queue.", &msg.author).await;
         return;
     }
-    let ready_queue: &mut Vec<User> = &mut data.get_mut::<ReadyQueue>().unwrap();
+    let queue_size = team_size(data.get::<Config>().unwrap()) * 2;
+    let ready_queue: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).ready_queue;
     if ready_queue.contains(&msg.author) {
         let response = MessageBuilder::new()
             .mention(&msg.author)
@@ -773,19 +2232,55 @@ pub(crate) async fn handle_ready(context: Context, msg: Message) {
         return;
     }
     ready_queue.push(msg.author.clone());
+    let ready_count = ready_queue.len();
+    show_ready_check(&context.http, msg.channel_id, &mut data).await;
+    save_state(&data);
     let response = MessageBuilder::new()
         .mention(&msg.author)
         .push(" is ready. Players ready: ")
-        .push(ready_queue.len().to_string())
-        .push("/10")
+        .push(format!("{}/{}", ready_count, queue_size))
         .build();
     if let Err(why) = msg.channel_id.say(&context.http, &response).await {
         eprintln!("Error sending message: {:?}", why);
     }
 
-    if ready_queue.len() >= 10 {
-        println!("Launching server...");
-        let draft: &Draft = &data.get::<Draft>().unwrap();
+    if ready_count >= queue_size {
+        let server = match series_server(&data, msg.channel_id) {
+            Some(server) => Ok(server),
+            None => match free_servers(&data, msg.channel_id) {
+                Ok(servers) => {
+                    // the lobby is marked as launching while the lock is released to ask the provider which servers are in use
+                    lobby_mut(&mut data, msg.channel_id).state.state = State::Live;
+                    let game_server = data.get::<GameServer>().unwrap().clone();
+                    drop(data);
+                    let server = allocate_server(game_server.as_ref(), servers).await;
+                    data = context.data.write().await;
+                    // `.cancel` may have been used while the lock was released
+                    if lobby(&data, msg.channel_id).state.state != State::Live { return; }
+                    lobby_mut(&mut data, msg.channel_id).state.state = State::Ready;
+                    // another lobby may have taken the server meanwhile
+                    server.and_then(|server| match free_servers(&data, msg.channel_id) {
+                        Ok(servers) if servers.iter().any(|s| s.id == server.id) => Ok(server),
+                        _ => Err(format!("server {} was taken by another lobby", &server.id)),
+                    })
+                }
+                Err(why) => Err(why),
+            },
+        };
+        let server = match server {
+            Ok(server) => server,
+            Err(why) => {
+                // take the last player back out of the ready queue so their next `.ready` retries the launch
+                let ready_queue: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).ready_queue;
+                ready_queue.retain(|u| u.id != msg.author.id);
+                save_state(&data);
+                send_simple_tagged_msg(&context, &msg, &format!(" unable to start the match, {}. Type `.ready` again to retry once a server is free.", why), &msg.author).await;
+                return;
+            }
+        };
+        println!("Launching server {}...", &server.id);
+        lobby_mut(&mut data, msg.channel_id).server_id = Some(String::from(&server.id));
+        let draft: &Draft = &lobby(&data, msg.channel_id).draft;
         let steam_id_cache: &HashMap<u64, String> = &data.get::<SteamIdCache>().unwrap();
         let mut team_a_steam_ids: Vec<String> = draft.team_a
             .iter()
@@ -794,11 +2289,6 @@ pub(crate) async fn handle_ready(context: Context, msg: Message) {
         for team_a_steam_id in &mut team_a_steam_ids {
             team_a_steam_id.replace_range(6..7, "1");
         }
-        let mut team_a_steam_id_str: String = team_a_steam_ids
-            .iter()
-            .map(|s| format!("{},", s))
-            .collect();
-        team_a_steam_id_str = String::from(&team_a_steam_id_str[..team_a_steam_id_str.len() - 1]);
         let mut team_b_steam_ids: Vec<String> = draft.team_b
             .iter()
             .map(|u| steam_id_cache.get(u.id.as_u64()).unwrap().to_string())
@@ -806,74 +2296,83 @@ pub(crate) async fn handle_ready(context: Context, msg: Message) {
         for team_b_steam_id in &mut team_b_steam_ids {
             team_b_steam_id.replace_range(6..7, "1");
         }
-        let mut team_b_steam_id_str: String = team_b_steam_ids
-            .iter()
-            .map(|s| format!("{},", s))
-            .collect();
-        team_b_steam_id_str = String::from(&team_b_steam_id_str[..team_b_steam_id_str.len() - 1]);
-        let response = MessageBuilder::new()
-            .push("All players are ready. Server is starting...")
-            .build();
-        if let Err(why) = msg.channel_id.say(&context.http, &response).await {
-            eprintln!("Error sending message: {:?}", why);
-        }
-        let team_ct: String;
-        let team_t: String;
+        let team_ct: Vec<String>;
+        let team_t: Vec<String>;
         if draft.team_b_start_side == "ct" {
-            team_ct = team_b_steam_id_str;
-            team_t = team_a_steam_id_str;
+            team_ct = team_b_steam_ids;
+            team_t = team_a_steam_ids;
         } else {
-            team_ct = team_a_steam_id_str;
-            team_t = team_b_steam_id_str;
+            team_ct = team_a_steam_ids;
+            team_t = team_b_steam_ids;
         }
 
         println!("Starting server with the following params:");
-        println!("team1_steam_ids:'{}'", &team_t);
-        println!("team2_steam_ids:'{}'", &team_ct);
+        println!("team1_steam_ids:'{}'", team_t.join(","));
+        println!("team2_steam_ids:'{}'", team_ct.join(","));
 
         let config: &Config = data.get::<Config>().unwrap();
-        let client = reqwest::Client::new();
-        let dathost_username = &config.dathost.username;
-        let dathost_password: Option<String> = Some(String::from(&config.dathost.password));
-        let server_id = &config.server.id;
-        let match_end_url = if &config.dathost.match_end_url == &None { "" } else { &config.dathost.match_end_url.as_ref().unwrap() };
-        let start_match_url = String::from("https://dathost.net/api/0.1/matches");
-        println!("match_end_webhook_url:'{}'", &match_end_url);
-        println!("game_server_id:'{}'", &server_id);
-        let mut auth_str = config.scrimbot_api_config.scrimbot_api_user.clone().unwrap();
-        auth_str.push_str(":");
-        auth_str.push_str(&*config.scrimbot_api_config.scrimbot_api_password.clone().unwrap());
-        let base64 = base64::encode(auth_str);
-        let mut auth_str = String::from("Basic ");
-        auth_str.push_str(&base64);
-        let resp = client
-            .post(&start_match_url)
-            .form(&[("game_server_id", &server_id),
-                ("team1_steam_ids", &&team_t),
-                ("team2_steam_ids", &&team_ct),
-                ("enable_pause", &&String::from("true")),
-                ("webhook_authorization_header", &&auth_str),
-                ("match_end_webhook_url", &&match_end_url.to_string())])
-            .basic_auth(&dathost_username, dathost_password)
-            .send()
-            .await
-            .unwrap();
-        println!("Start match response code - {}", &resp.status());
-
-        if resp.status().is_success() {
-            let steam_web_url: String = format!("steam://connect/{}", &config.server.url);
-            let port_start = &config.server.url.find(':').unwrap_or_else(|| 0 as usize) + 1;
-            let gotv_port = String::from(&config.server.url[port_start..config.server.url.len()]).parse::<i64>().unwrap_or_else(|_| 0) + 1;
-            let gotv_url = format!("{}{}", &config.server.url[0..port_start], gotv_port);
-            send_simple_msg(&context, &msg, &format!("Server has started.\n\n**Connection info:**\nLink: {}\nConsole: \
-            `connect {}`\n\n_GOTV Info:_\nLink: {}\nConsole: `connect {}`", steam_web_url, &config.server.url, &format!("steam://connect/{}", gotv_url), gotv_url)).await;
+        let game_server = data.get::<GameServer>().unwrap().clone();
+        let settings = MatchSettings {
+            team1_steam_ids: team_t,
+            team2_steam_ids: team_ct,
+            match_end_url: config.dathost.as_ref().and_then(|d| d.match_end_url.clone()),
+            webhook_authorization_header: webhook_authorization_header(config),
+        };
+        // fall back to loading the map by name if it was removed from the pool since it was picked
+        let map = data.get::<Maps>().unwrap().iter()
+            .find(|m| m.name == draft.map)
+            .cloned()
+            .unwrap_or_else(|| MapEntry::new(&draft.map));
+        let teamname_cache = data.get::<TeamNameCache>().unwrap();
+        let team_a_name = teamname_cache.get(draft.captain_a.as_ref().unwrap().id.as_u64())
+            .map(String::from)
+            .unwrap_or_else(|| format!("Team {}", &draft.captain_a.as_ref().unwrap().name));
+        let team_b_name = teamname_cache.get(draft.captain_b.as_ref().unwrap().id.as_u64())
+            .map(String::from)
+            .unwrap_or_else(|| format!("Team {}", &draft.captain_b.as_ref().unwrap().name));
+        // DatHost's team 1 starts T, while mp_teamname_1 names the team starting CT
+        let team_t;
+        let team_ct;
+        let team_t_name;
+        let team_ct_name;
+        if draft.team_b_start_side == "ct" {
+            team_t = (draft.team_a.clone(), draft.captain_a.clone());
+            team_ct = (draft.team_b.clone(), draft.captain_b.clone());
+            team_t_name = team_a_name;
+            team_ct_name = team_b_name;
         } else {
-            send_simple_msg(&context, &msg, &format!("Server failed to start, match POST response code: {}", &resp.status().as_str())).await;
+            team_t = (draft.team_b.clone(), draft.captain_b.clone());
+            team_ct = (draft.team_a.clone(), draft.captain_a.clone());
+            team_t_name = team_b_name;
+            team_ct_name = team_a_name;
+        }
+        let (team_a, team_b) = (draft.team_a.clone(), draft.team_b.clone());
+        let (team_a_channel_id, team_b_channel_id) = (config.discord.team_a_channel_id, config.discord.team_b_channel_id);
+        // the lock is released while the server starts, which can take a while, `.ready` and the other setup
+        // commands are ignored meanwhile as the lobby is no longer in the ready phase
+        lobby_mut(&mut data, msg.channel_id).state.state = State::Live;
+        save_state(&data);
+        drop(data);
+        let response = MessageBuilder::new()
+            .push("All players are ready. Server is starting...")
+            .build();
+        if let Err(why) = msg.channel_id.say(&context.http, &response).await {
+            eprintln!("Error sending message: {:?}", why);
+        }
+        let start_result = launch_match(game_server.as_ref(), &server, &map, &settings, &team_ct_name, &team_t_name).await;
+
+        if start_result.is_ok() {
+            let steam_web_url: String = format!("steam://connect/{}", &server.url);
+            let port_start = server.url.find(':').unwrap_or(0) + 1;
+            let gotv_port = String::from(&server.url[port_start..server.url.len()]).parse::<i64>().unwrap_or(0) + 1;
+            let gotv_url = format!("{}{}", &server.url[0..port_start], gotv_port);
+            send_simple_msg(&context, &msg, &format!("Server has started.\n\n**Connection info:**\nLink: {}\nConsole: \
+            `connect {}`\n\n_GOTV Info:_\nLink: {}\nConsole: `connect {}`", steam_web_url, &server.url, &format!("steam://connect/{}", gotv_url), gotv_url)).await;
+        } else if let Err(why) = &start_result {
+            send_simple_msg(&context, &msg, &format!("Server failed to start, {}", why)).await;
         }
-        let draft: &Draft = data.get::<Draft>().unwrap();
-        let config: &Config = &data.get::<Config>().unwrap();
-        if let Some(team_a_channel_id) = config.discord.team_a_channel_id {
-            for user in &draft.team_a {
+        if let Some(team_a_channel_id) = team_a_channel_id {
+            for user in &team_a {
                 if let Some(guild) = &msg.guild(&context.cache).await {
                     if let Err(why) = guild.move_member(&context.http, user.id, team_a_channel_id).await {
                         println!("Cannot move user: {:?}", why);
@@ -881,8 +2380,8 @@ pub(crate) async fn handle_ready(context: Context, msg: Message) {
                 }
             }
         }
-        if let Some(team_b_channel_id) = config.discord.team_b_channel_id {
-            for user in &draft.team_b {
+        if let Some(team_b_channel_id) = team_b_channel_id {
+            for user in &team_b {
                 if let Some(guild) = &msg.guild(&context.cache).await {
                     if let Err(why) = guild.move_member(&context.http, user.id, team_b_channel_id).await {
                         println!("Cannot move user: {:?}", why);
@@ -890,105 +2389,223 @@ pub(crate) async fn handle_ready(context: Context, msg: Message) {
                 }
             }
         }
-        if let Some(teamname_cache) = data.get::<TeamNameCache>() {
-            let send_command_url = format!("https://dathost.net/api/0.1/game-servers/{}/console", &config.server.id);
-            let dathost_password: Option<String> = Some(String::from(&config.dathost.password));
-            let default_team_a_name = &format!("Team {}", &draft.captain_a.as_ref().unwrap().name);
-            let default_team_b_name = &format!("Team {}", &draft.captain_b.as_ref().unwrap().name);
-            let team_a_name = teamname_cache.get(&draft.captain_a.as_ref().unwrap().id.as_u64())
-                .unwrap_or(default_team_a_name);
-            let team_b_name = teamname_cache.get(&draft.captain_b.as_ref().unwrap().id.as_u64())
-                .unwrap_or(default_team_b_name);
-            let team_one_command;
-            let team_two_command;
-            if draft.team_b_start_side == "ct" {
-                team_one_command = format!("mp_teamname_1 {}", &team_b_name);
-                team_two_command = format!("mp_teamname_2 {}", &team_a_name);
-            } else {
-                team_one_command = format!("mp_teamname_1 {}", &team_a_name);
-                team_two_command = format!("mp_teamname_2 {}", &team_b_name);
-            }
-            if let Err(resp) = client
-                .post(&send_command_url)
-                .form(&[("line", &team_one_command)])
-                .basic_auth(&dathost_username, dathost_password)
-                .send()
-                .await {
-                eprintln!("Error setting team name 1: {:?}", resp);
-            }
-            let dathost_password: Option<String> = Some(String::from(&config.dathost.password));
-            if let Err(resp) = client
-                .post(&send_command_url)
-                .form(&[("line", &team_two_command)])
-                .basic_auth(&dathost_username, dathost_password)
-                .send()
-                .await {
-                eprintln!("Error setting team name 2: {:?}", resp);
+        let mut data = context.data.write().await;
+        let started = start_result.is_ok();
+        let series_map_index = lobby(&data, msg.channel_id).series.as_ref().map(|s| s.map_index);
+        // a series stays on the server its first map started on as its players are still connected between maps,
+        // any other match keeps its server busy through its live match from here on
+        if !matches!(series_map_index, Some(map_index) if started || map_index > 0) {
+            lobby_mut(&mut data, msg.channel_id).server_id = None;
+        }
+        if let Ok(dathost_match_id) = &start_result {
+            let steam_id_cache: &HashMap<u64, String> = data.get::<SteamIdCache>().unwrap();
+            let to_players = |team: u8, users: &Vec<User>| -> Vec<MatchPlayer> {
+                users.iter()
+                    .map(|u| MatchPlayer {
+                        team,
+                        discord_id: *u.id.as_u64(),
+                        name: String::from(&u.name),
+                        steam_id: steam_id_cache.get(u.id.as_u64()).cloned().unwrap_or_default(),
+                    })
+                    .collect()
+            };
+            let mut players = to_players(1, &team_t.0);
+            players.append(&mut to_players(2, &team_ct.0));
+            let record = MatchRecord {
+                id: 0,
+                dathost_match_id: dathost_match_id.clone(),
+                map: String::from(&map.name),
+                team1_name: String::from(&team_t_name),
+                team2_name: String::from(&team_ct_name),
+                team1_captain_id: team_t.1.as_ref().map(|u| *u.id.as_u64()),
+                team2_captain_id: team_ct.1.as_ref().map(|u| *u.id.as_u64()),
+                team1_score: None,
+                team2_score: None,
+                started_at: String::new(),
+                finished_at: None,
+                players,
+            };
+            let db: &Database = data.get::<Db>().unwrap();
+            match db.record_match(&record) {
+                Ok(id) => println!("Recorded match #{}", id),
+                Err(why) => eprintln!("Error recording match history: {:?}", why),
             }
         }
+        let config: &Config = data.get::<Config>().unwrap();
         if let Some(post_start_msg) = &config.post_setup_msg {
             if let Err(why) = msg.channel_id.say(&context.http, &post_start_msg).await {
                 eprintln!("Error sending message: {:?}", why);
             }
         }
-        // reset to queue state
-        let user_queue: &mut Vec<User> = data.get_mut::<UserQueue>().unwrap();
-        user_queue.clear();
-        let ready_queue: &mut Vec<User> = data.get_mut::<ReadyQueue>().unwrap();
-        ready_queue.clear();
-        let draft: &mut Draft = &mut data.get_mut::<Draft>().unwrap();
-        draft.team_a = vec![];
-        draft.team_b = vec![];
-        draft.captain_a = None;
-        draft.captain_b = None;
-        draft.current_picker = None;
-        let bot_state: &mut StateContainer = &mut data.get_mut::<BotState>().unwrap();
-        bot_state.state = State::Queue;
-        let queue_msgs: &mut HashMap<u64, String> = &mut data.get_mut::<QueueMessages>().unwrap();
-        queue_msgs.clear();
+        let match_id = start_result.ok().flatten();
+        if let Some(match_id) = &match_id {
+            let live_matches: &mut HashMap<String, LiveMatch> = data.get_mut::<LiveMatches>().unwrap();
+            live_matches.insert(String::from(match_id), LiveMatch {
+                channel_id: *msg.channel_id.as_u64(),
+                team1_name: team_t_name,
+                team2_name: team_ct_name,
+                server_id: Some(String::from(&server.id)),
+                started_at: Local::now().timestamp(),
+            });
+        }
+        // `.cancel` may have been used while the lock was released, the match is recorded but the lobby is left alone
+        if lobby(&data, msg.channel_id).state.state != State::Live {
+            save_state(&data);
+            return;
+        }
+        if lobby(&data, msg.channel_id).series.is_some() {
+            // the teams stay together until the series is over, a failed launch is retried with `.ready`
+            let lobby = lobby_mut(&mut data, msg.channel_id);
+            lobby.ready_queue.clear();
+            lobby.state.state = if started { State::Live } else { State::Ready };
+            lobby.series.as_mut().unwrap().match_id = match_id;
+            save_state(&data);
+            return;
+        }
+        let promoted = reset_lobby(&mut data, msg.channel_id);
+        save_state(&data);
+        announce_promoted(&context.http, msg.channel_id, &promoted, queue_size, &data).await;
+        let pop = if promoted.is_empty() { None } else { queue_pop(&data, msg.channel_id) };
+        drop(data);
+        if let Some(pop) = pop {
+            queue_popped(&context, msg.channel_id, pop).await;
+        }
+    }
+}
+
+/// Resets a lobby to the queue phase once its match has been launched, or its series is over.
+/// The waitlist carries over into the new queue, returns the players moved into it.
+fn reset_lobby(data: &mut TypeMap, channel_id: ChannelId) -> Vec<User> {
+    let user_queue: &mut Vec<User> = &mut lobby_mut(data, channel_id).user_queue;
+    user_queue.clear();
+    let ready_queue: &mut Vec<User> = &mut lobby_mut(data, channel_id).ready_queue;
+    ready_queue.clear();
+    let draft: &mut Draft = &mut lobby_mut(data, channel_id).draft;
+    draft.team_a = vec![];
+    draft.team_b = vec![];
+    draft.captain_a = None;
+    draft.captain_b = None;
+    draft.current_picker = None;
+    lobby_mut(data, channel_id).veto = None;
+    lobby_mut(data, channel_id).series = None;
+    lobby_mut(data, channel_id).ready_deadline = None;
+    lobby_mut(data, channel_id).ready_message_id = None;
+    lobby_mut(data, channel_id).server_id = None;
+    let bot_state: &mut StateContainer = &mut lobby_mut(data, channel_id).state;
+    bot_state.state = State::Queue;
+    let queue_msgs: &mut HashMap<u64, String> = &mut lobby_mut(data, channel_id).queue_messages;
+    queue_msgs.clear();
+    promote_waitlist(data, channel_id)
+}
+
+/// Adds a finished series map to the series score once its match end webhook arrives, then either sets up the
+/// side pick for the next map or announces the winner and frees up the lobby
+pub(crate) async fn series_map_ended(http: &Http, data: &mut TypeMap, channel_id: ChannelId, match_id: &str, team1_score: u64, team2_score: u64) {
+    let draft = match data.get::<Lobbies>().unwrap().get(channel_id.as_u64()) {
+        // other matches launched from the same channel don't count towards the series
+        Some(Lobby { state, series: Some(series), draft, .. })
+            if state.state == State::Live && series.match_id.as_deref() == Some(match_id) => draft,
+        _ => return,
+    };
+    let (team_a_name, team_b_name) = draft_team_names(data, draft);
+    // DatHost's team 1 is the team that started T
+    let (score_a, score_b) = if draft.team_b_start_side == "ct" { (team1_score, team2_score) } else { (team2_score, team1_score) };
+    let series: &mut Series = lobby_mut(data, channel_id).series.as_mut().unwrap();
+    if score_a > score_b {
+        series.score_a += 1;
+    } else if score_b > score_a {
+        series.score_b += 1;
+    }
+    series.map_index += 1;
+    let mut response = MessageBuilder::new();
+    response.push_bold_line("Series score:")
+        .push_line(format!("{} **{}** - **{}** {}", &team_a_name, series.score_a, series.score_b, &team_b_name));
+    if series.score_a >= 2 || series.score_b >= 2 || series.map_index >= series.maps.len() {
+        match series.score_a.cmp(&series.score_b) {
+            std::cmp::Ordering::Greater => response.push_bold_line(format!("{} wins the series!", &team_a_name)),
+            std::cmp::Ordering::Less => response.push_bold_line(format!("{} wins the series!", &team_b_name)),
+            std::cmp::Ordering::Equal => response.push_bold_line("The series ended in a draw"),
+        };
+        let promoted = reset_lobby(data, channel_id);
+        if !promoted.is_empty() {
+            response.push_line("").push("Moved up from the waitlist into the queue:");
+            for user in &promoted {
+                response.push(" ").mention(user);
+            }
+        }
+    } else {
+        let map = String::from(&series.maps[series.map_index]);
+        let map_number = series.map_index + 1;
+        lobby_mut(data, channel_id).draft.map = String::from(&map);
+        lobby_mut(data, channel_id).state.state = State::SidePick;
+        let side_picker = side_picker(lobby(data, channel_id));
+        response.push_line("")
+            .push(format!("Map {} is `{}`. ", map_number, &map))
+            .mention(&side_picker)
+            .push(" type `.ct` or `.t` to pick a starting side.");
+    }
+    save_state(data);
+    if let Err(why) = channel_id.say(http, response.build()).await {
+        eprintln!("Error sending message: {:?}", why);
     }
 }
 
-pub(crate) async fn handle_unready(context: Context, msg: Message) {
+pub(crate) async fn handle_unready(context: Context, msg: CommandMessage) {
+    if !lobby_check(&context, &msg).await { return; }
     let mut data = context.data.write().await;
-    let bot_state: &StateContainer = data.get_mut::<BotState>().unwrap();
+    let bot_state: &StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
     if bot_state.state != State::Ready {
         send_simple_tagged_msg(&context, &msg, " command ignored. The draft has not been completed yet", &msg.author).await;
         return;
     }
-    let user_queue: &Vec<User> = &data.get::<UserQueue>().unwrap();
+    let user_queue: &Vec<User> = &lobby(&data, msg.channel_id).user_queue;
     if !user_queue.contains(&msg.author) {
         send_simple_tagged_msg(&context, &msg, " you are not in the queue.", &msg.author).await;
         return;
     }
-    let ready_queue: &mut Vec<User> = &mut data.get_mut::<ReadyQueue>().unwrap();
-    let index = ready_queue.iter().position(|r| r.id == msg.author.id).unwrap();
+    let ready_queue: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).ready_queue;
+    let index = match ready_queue.iter().position(|r| r.id == msg.author.id) {
+        Some(index) => index,
+        None => {
+            send_simple_tagged_msg(&context, &msg, " you are not `.ready`.", &msg.author).await;
+            return;
+        }
+    };
     ready_queue.remove(index);
+    show_ready_check(&context.http, msg.channel_id, &mut data).await;
+    save_state(&data);
     send_simple_tagged_msg(&context, &msg, " is no longer `.ready`.", &msg.author).await;
 }
 
-pub(crate) async fn handle_cancel(context: Context, msg: Message) {
+pub(crate) async fn handle_cancel(context: Context, msg: CommandMessage) {
     if !admin_check(&context, &msg, true).await { return; }
+    if !lobby_check(&context, &msg).await { return; }
     let mut data = context.data.write().await;
-    let bot_state: &StateContainer = &data.get::<BotState>().unwrap();
+    let bot_state: &StateContainer = &lobby(&data, msg.channel_id).state;
     if bot_state.state == State::Queue {
         send_simple_tagged_msg(&context, &msg, " command only valid during `.start` process", &msg.author).await;
         return;
     }
-    let ready_queue: &mut Vec<User> = data.get_mut::<ReadyQueue>().unwrap();
+    let ready_queue: &mut Vec<User> = &mut lobby_mut(&mut data, msg.channel_id).ready_queue;
     ready_queue.clear();
-    let draft: &mut Draft = &mut data.get_mut::<Draft>().unwrap();
+    let draft: &mut Draft = &mut lobby_mut(&mut data, msg.channel_id).draft;
     draft.team_a = vec![];
     draft.team_b = vec![];
     draft.captain_a = None;
     draft.captain_b = None;
     draft.current_picker = None;
-    let bot_state: &mut StateContainer = &mut data.get_mut::<BotState>().unwrap();
+    lobby_mut(&mut data, msg.channel_id).veto = None;
+    lobby_mut(&mut data, msg.channel_id).series = None;
+    lobby_mut(&mut data, msg.channel_id).ready_deadline = None;
+    lobby_mut(&mut data, msg.channel_id).ready_message_id = None;
+    lobby_mut(&mut data, msg.channel_id).server_id = None;
+    let bot_state: &mut StateContainer = &mut lobby_mut(&mut data, msg.channel_id).state;
     bot_state.state = State::Queue;
+    save_state(&data);
     send_simple_tagged_msg(&context, &msg, " `.start` process cancelled.", &msg.author).await;
 }
 
-pub(crate) async fn handle_stats(context: Context, msg: Message) {
+pub(crate) async fn handle_stats(context: Context, msg: CommandMessage) {
     let data = context.data.write().await;
     let config: &Config = data.get::<Config>().unwrap();
     if &config.scrimbot_api_config.scrimbot_api_url == &None {
@@ -1171,9 +2788,8 @@ pub(crate) async fn handle_stats(context: Context, msg: Message) {
     }
 }
 
-pub(crate) async fn handle_teamname(context: Context, msg: Message) {
+pub(crate) async fn handle_teamname(context: Context, msg: CommandMessage) {
     let mut data = context.data.write().await;
-    let teamname_cache: &mut HashMap<u64, String> = &mut data.get_mut::<TeamNameCache>().unwrap();
     let split_content = msg.content.trim().split(' ').collect::<Vec<_>>();
     if split_content.len() < 2 {
         send_simple_tagged_msg(&context, &msg, " invalid message formatting. Example: `.teamname TeamName`", &msg.author).await;
@@ -1184,12 +2800,25 @@ pub(crate) async fn handle_teamname(context: Context, msg: Message) {
         send_simple_tagged_msg(&context, &msg, &format!(" team name is over the character limit by {}.", teamname.len() - 18), &msg.author).await;
         return;
     }
+    let db: &Database = data.get::<Db>().unwrap();
+    if let Err(why) = db.set_team_name(*msg.author.id.as_u64(), &teamname) {
+        eprintln!("Error saving team name: {:?}", why);
+        send_simple_tagged_msg(&context, &msg, " sorry, something went wrong saving your team name", &msg.author).await;
+        return;
+    }
+    let teamname_cache: &mut HashMap<u64, String> = &mut data.get_mut::<TeamNameCache>().unwrap();
     teamname_cache.insert(*msg.author.id.as_u64(), String::from(&teamname));
-    write_to_file(String::from("teamnames.json"), serde_json::to_string(teamname_cache).unwrap()).await;
     send_simple_tagged_msg(&context, &msg, &format!(" custom team name successfully set to `{}`", &teamname), &msg.author).await;
 }
 
-pub(crate) async fn send_simple_msg(context: &Context, msg: &Message, text: &str) {
+/// The `Authorization` header value the match end webhook is called with, built from the scrimbot-api credentials
+pub(crate) fn webhook_authorization_header(config: &Config) -> Option<String> {
+    let user = config.scrimbot_api_config.scrimbot_api_user.as_ref()?;
+    let password = config.scrimbot_api_config.scrimbot_api_password.as_ref()?;
+    Some(format!("Basic {}", base64::encode(format!("{}:{}", user, password))))
+}
+
+pub(crate) async fn send_simple_msg(context: &Context, msg: &CommandMessage, text: &str) {
     let response = MessageBuilder::new()
         .push(text)
         .build();
@@ -1198,7 +2827,7 @@ pub(crate) async fn send_simple_msg(context: &Context, msg: &Message, text: &str
     }
 }
 
-pub(crate) async fn send_simple_tagged_msg(context: &Context, msg: &Message, text: &str, mentioned: &User) -> Option<Message> {
+pub(crate) async fn send_simple_tagged_msg(context: &Context, msg: &CommandMessage, text: &str, mentioned: &User) -> Option<Message> {
     let response = MessageBuilder::new()
         .mention(mentioned)
         .push(text)
@@ -1211,7 +2840,99 @@ pub(crate) async fn send_simple_tagged_msg(context: &Context, msg: &Message, tex
     }
 }
 
-pub(crate) async fn admin_check(context: &Context, msg: &Message, print_msg: bool) -> bool {
+/// Makes sure the message's channel has a lobby, letting the user know if it isn't a lobby channel.
+/// Without `lobbies` configured every channel gets its own lobby on first use.
+pub(crate) async fn lobby_check(context: &Context, msg: &CommandMessage) -> bool {
+    let mut data = context.data.write().await;
+    let config: &Config = data.get::<Config>().unwrap();
+    let channel_id = *msg.channel_id.as_u64();
+    if let Some(lobbies) = &config.lobbies {
+        if !lobbies.iter().any(|l| l.channel_id == channel_id) {
+            send_simple_tagged_msg(context, msg, " this channel is not a scrim lobby, queue commands can only be used in lobby channels", &msg.author).await;
+            return false;
+        }
+    }
+    data.get_mut::<Lobbies>().unwrap().entry(channel_id).or_insert_with(Lobby::new);
+    true
+}
+
+pub(crate) fn lobby(data: &TypeMap, channel_id: ChannelId) -> &Lobby {
+    data.get::<Lobbies>().unwrap().get(channel_id.as_u64()).unwrap()
+}
+
+pub(crate) fn lobby_mut(data: &mut TypeMap, channel_id: ChannelId) -> &mut Lobby {
+    data.get_mut::<Lobbies>().unwrap().get_mut(channel_id.as_u64()).unwrap()
+}
+
+/// Players per team, 5 unless `team_size` is configured
+pub(crate) fn team_size(config: &Config) -> usize {
+    config.team_size.unwrap_or(5)
+}
+
+/// The servers a lobby can launch matches on in order of preference,
+/// the lobby's own server first followed by the top level `server` and the `servers` pool
+pub(crate) fn server_pool(config: &Config, channel_id: ChannelId) -> Vec<&ServerConfig> {
+    let lobby_server = config.lobbies.iter()
+        .flatten()
+        .find(|l| l.channel_id == *channel_id.as_u64())
+        .and_then(|l| l.server.as_ref());
+    let mut pool: Vec<&ServerConfig> = Vec::new();
+    for server in lobby_server.into_iter().chain(std::iter::once(&config.server)).chain(config.servers.iter().flatten()) {
+        if !pool.iter().any(|s| s.id == server.id) {
+            pool.push(server);
+        }
+    }
+    pool
+}
+
+/// The server a lobby's series is played on, its players are still on it in between maps
+fn series_server(data: &TypeMap, channel_id: ChannelId) -> Option<ServerConfig> {
+    let server_id = lobby(data, channel_id).server_id.as_ref()?;
+    server_pool(data.get::<Config>().unwrap(), channel_id).into_iter()
+        .find(|s| &s.id == server_id)
+        .cloned()
+}
+
+/// The servers in a lobby's pool that aren't hosting or launching a match the bot knows of
+fn free_servers(data: &TypeMap, channel_id: ChannelId) -> Result<Vec<ServerConfig>, String> {
+    let pool = server_pool(data.get::<Config>().unwrap(), channel_id);
+    let busy = busy_servers(data.get::<LiveMatches>().unwrap(), data.get::<Lobbies>().unwrap(), Local::now().timestamp());
+    let free: Vec<ServerConfig> = pool.iter()
+        .filter(|s| !busy.contains(&s.id))
+        .map(|s| (*s).clone())
+        .collect();
+    if free.is_empty() {
+        return Err(format!("all {} server(s) are busy", pool.len()));
+    }
+    Ok(free)
+}
+
+/// The first of the servers without players online. This catches the matches the bot can't track,
+/// i.e. RCON matches without a match id and matches started outside the bot. Servers that can't be queried are skipped.
+async fn allocate_server(provider: &dyn GameServerProvider, servers: Vec<ServerConfig>) -> Result<ServerConfig, String> {
+    let count = servers.len();
+    for server in servers {
+        match provider.status(&server).await {
+            Ok(status) if status.players_online == 0 => return Ok(server),
+            Ok(status) => println!("Server {} has {} player(s) online, skipping it", &server.id, status.players_online),
+            Err(why) => eprintln!("Error checking server {}: {}", &server.id, why),
+        }
+    }
+    Err(format!("all {} free server(s) have players on them", count))
+}
+
+/// The ids of the servers with a match launched by the bot that hasn't ended yet, a launch in progress or a series.
+/// Matches without a match end webhook after `MATCH_TIMEOUT` are assumed to be over so their server isn't lost for good.
+/// Matches without a match id (RCON) can't be tracked and only block their server while they are launched.
+fn busy_servers(live_matches: &HashMap<String, LiveMatch>, lobbies: &HashMap<u64, Lobby>, now: i64) -> HashSet<String> {
+    let live = live_matches.values()
+        .filter(|m| now - m.started_at < MATCH_TIMEOUT.as_secs() as i64)
+        .filter_map(|m| m.server_id.clone());
+    let launching = lobbies.values().filter_map(|l| l.server_id.clone());
+    live.chain(launching).collect()
+}
+
+pub(crate) async fn admin_check(context: &Context, msg: &CommandMessage, print_msg: bool) -> bool {
     let data = context.data.write().await;
     let config: &Config = data.get::<Config>().unwrap();
     let role_name = context.cache.role(msg.guild_id.unwrap(), config.discord.admin_role_id).await.unwrap().name;
@@ -1328,3 +3049,217 @@ pub(crate) async fn populate_unicode_emojis() -> HashMap<char, String> {
     map.insert('z', String::from("🇿"));
     map
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_server::fake::FakeProvider;
+
+    fn live_match(server_id: &str, started_at: i64) -> LiveMatch {
+        LiveMatch {
+            channel_id: 1,
+            team1_name: String::from("Team 1"),
+            team2_name: String::from("Team 2"),
+            server_id: Some(String::from(server_id)),
+            started_at,
+        }
+    }
+
+    fn user(id: u64) -> User {
+        let mut user = User::default();
+        user.id = UserId(id);
+        user
+    }
+
+    /// A draft between captains 1 and 2 with `team_a_len` and `team_b_len` players picked so far
+    fn draft(pick_index: usize, team_a_len: usize, team_b_len: usize) -> Draft {
+        let mut draft = Lobby::new().draft;
+        draft.captain_a = Some(user(1));
+        draft.captain_b = Some(user(2));
+        draft.team_a = (0..team_a_len as u64).map(|i| user(10 + i)).collect();
+        draft.team_b = (0..team_b_len as u64).map(|i| user(20 + i)).collect();
+        draft.pick_index = pick_index;
+        draft
+    }
+
+    fn pick_sequence(pick_order: &str, picks: usize) -> String {
+        (0..picks).map(|i| picking_team(pick_order, i, 0, 0, 5)).collect()
+    }
+
+    #[test]
+    fn picking_team_follows_the_pick_order() {
+        assert_eq!(pick_sequence("AB", 8), "ABABABAB");
+        assert_eq!(pick_sequence("ABBA", 8), "ABBAABBA");
+        assert_eq!(pick_sequence("ABBABA", 8), "ABBABAAB");
+    }
+
+    #[test]
+    fn picking_team_skips_a_full_team() {
+        assert_eq!(picking_team("AB", 2, 2, 1, 2), 'B');
+        assert_eq!(picking_team("ABBA", 1, 1, 2, 2), 'A');
+        assert_eq!(picking_team("ABBA", 3, 2, 1, 2), 'B');
+    }
+
+    #[test]
+    fn next_picker_is_the_captain_of_the_picking_team() {
+        assert_eq!(next_picker(&draft(0, 1, 1), "ABBA", 5), Some(user(1)));
+        assert_eq!(next_picker(&draft(1, 2, 1), "ABBA", 5), Some(user(2)));
+        assert_eq!(next_picker(&draft(4, 5, 4), "AB", 5), Some(user(2)));
+        assert_eq!(next_picker(&draft(8, 5, 5), "AB", 5), None);
+    }
+
+    #[test]
+    fn picks_left_in_turn_counts_consecutive_picks() {
+        assert_eq!(picks_left_in_turn(&draft(0, 1, 1), "AB", 5), 1);
+        assert_eq!(picks_left_in_turn(&draft(0, 1, 1), "ABBA", 5), 1);
+        assert_eq!(picks_left_in_turn(&draft(1, 2, 1), "ABBA", 5), 2);
+        assert_eq!(picks_left_in_turn(&draft(2, 2, 2), "ABBA", 5), 1);
+        assert_eq!(picks_left_in_turn(&draft(1, 2, 1), "ABBABA", 5), 2);
+        assert_eq!(picks_left_in_turn(&draft(3, 3, 3), "ABBABA", 5), 1);
+        // once team A is full every remaining pick is B's
+        assert_eq!(picks_left_in_turn(&draft(4, 5, 2), "AB", 5), 3);
+    }
+
+    fn rating_difference(ratings: &[f64], in_team_a: &[bool]) -> f64 {
+        ratings.iter().zip(in_team_a).map(|(rating, a)| if *a { *rating } else { -rating }).sum::<f64>().abs()
+    }
+
+    #[test]
+    fn balanced_split_of_two_players() {
+        assert_eq!(balanced_split(&[1.2, 0.8]), vec![true, false]);
+    }
+
+    #[test]
+    fn balanced_split_of_ten_players_has_the_smallest_difference() {
+        let ratings = [1.9, 0.4, 1.1, 0.7, 1.3, 1.0, 0.9, 1.6, 0.5, 1.2];
+        let in_team_a = balanced_split(&ratings);
+        assert_eq!(in_team_a.iter().filter(|a| **a).count(), 5);
+        let smallest = (0u32..1 << 10)
+            .filter(|split| split.count_ones() == 5)
+            .map(|split| {
+                let split: Vec<bool> = (0..10).map(|i| split & (1 << i) != 0).collect();
+                rating_difference(&ratings, &split)
+            })
+            .fold(f64::MAX, f64::min);
+        assert!((rating_difference(&ratings, &in_team_a) - smallest).abs() < 1e-9);
+    }
+
+    #[test]
+    fn win_rate_rating_keeps_new_players_average() {
+        assert_eq!(win_rate_rating(0, 0), 1.0);
+        assert_eq!(win_rate_rating(1, 2), 1.0);
+        assert!((win_rate_rating(3, 4) - 4.0 / 3.0).abs() < 1e-9);
+        assert!((win_rate_rating(0, 4) - 1.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn parse_map_entry_accepts_arguments_in_any_order() {
+        let map = parse_map_entry(".addmap \"Dust 2\" 123456 de_dust2").unwrap();
+        assert_eq!((map.name.as_str(), map.map_name.as_str(), map.workshop_id), ("Dust 2", "de_dust2", Some(123456)));
+        let map = parse_map_entry(".addmap de_dust2 \"Dust 2\" 123456").unwrap();
+        assert_eq!((map.name.as_str(), map.map_name.as_str(), map.workshop_id), ("Dust 2", "de_dust2", Some(123456)));
+        let map = parse_map_entry(".addmap 123456 de_dust2").unwrap();
+        assert_eq!((map.name.as_str(), map.map_name.as_str(), map.workshop_id), ("de_dust2", "de_dust2", Some(123456)));
+    }
+
+    #[test]
+    fn parse_map_entry_rejects_an_unclosed_quote() {
+        assert!(parse_map_entry(".addmap de_dust2 \"Dust 2").is_err());
+    }
+
+    #[test]
+    fn parse_map_entry_reads_an_all_digit_argument_as_a_workshop_id() {
+        // without another argument there is no map name left
+        assert!(parse_map_entry(".addmap 123456").is_err());
+        assert_eq!(parse_map_entry(".addmap 2fort 123456").unwrap().workshop_id, Some(123456));
+    }
+
+    #[test]
+    fn parse_map_entry_rejects_two_workshop_ids() {
+        assert!(parse_map_entry(".addmap de_dust2 123456 654321").is_err());
+    }
+
+    fn vote(map: &str, voters: &[u64]) -> ReactionResult {
+        ReactionResult { map: String::from(map), emoji: String::new(), voters: voters.to_vec() }
+    }
+
+    #[test]
+    fn over_vote_limit_finds_players_with_too_many_votes() {
+        let results = [vote("de_dust2", &[1, 2, 3]), vote("de_inferno", &[1, 3]), vote("de_nuke", &[1])];
+        assert_eq!(over_vote_limit(&results, 1).into_iter().collect::<HashSet<u64>>(), [1, 3].iter().copied().collect());
+        assert_eq!(over_vote_limit(&results, 2), vec![1]);
+        assert!(over_vote_limit(&results, 3).is_empty());
+    }
+
+    #[test]
+    fn players_to_warn_only_warns_again_once_the_votes_changed() {
+        let mut warned_votes = HashMap::new();
+        let results = [vote("de_dust2", &[1, 2]), vote("de_inferno", &[1])];
+        assert_eq!(players_to_warn(&results, 1, &mut warned_votes), vec![1]);
+        // the votes couldn't be removed
+        assert!(players_to_warn(&results, 1, &mut warned_votes).is_empty());
+        let results = [vote("de_dust2", &[1, 2]), vote("de_inferno", &[1]), vote("de_nuke", &[1])];
+        assert_eq!(players_to_warn(&results, 1, &mut warned_votes), vec![1]);
+        // the votes were removed and cast again
+        let results = [vote("de_dust2", &[2]), vote("de_inferno", &[])];
+        assert!(players_to_warn(&results, 1, &mut warned_votes).is_empty());
+        let results = [vote("de_dust2", &[1, 2]), vote("de_inferno", &[1])];
+        assert_eq!(players_to_warn(&results, 1, &mut warned_votes), vec![1]);
+    }
+
+    /// The veto steps for a BO3 with `map_count` maps, `B` for a ban and `P` for a pick
+    fn veto_steps(map_count: usize) -> String {
+        let maps: Vec<String> = (0..map_count).map(|i| format!("de_map{}", i)).collect();
+        let mut veto = Veto::new(&maps, true);
+        (0..map_count - 1)
+            .map(|step| {
+                veto.step = step;
+                if is_pick_step(&veto) { 'P' } else { 'B' }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bo3_veto_picks_follow_the_first_bans() {
+        assert_eq!(veto_steps(3), "PP");
+        assert_eq!(veto_steps(4), "BPP");
+        assert_eq!(veto_steps(7), "BBPPBB");
+    }
+
+    #[test]
+    fn bo1_veto_only_bans() {
+        let maps: Vec<String> = (0..7).map(|i| format!("de_map{}", i)).collect();
+        let veto = Veto::new(&maps, false);
+        assert!(!is_pick_step(&veto));
+    }
+
+    #[test]
+    fn busy_servers_include_live_matches_and_launches() {
+        let mut live_matches = HashMap::new();
+        live_matches.insert(String::from("match-1"), live_match("server-1", 1000));
+        let mut lobbies = HashMap::new();
+        let mut launching = Lobby::new();
+        launching.server_id = Some(String::from("server-2"));
+        lobbies.insert(1, launching);
+        lobbies.insert(2, Lobby::new());
+        let busy = busy_servers(&live_matches, &lobbies, 1060);
+        assert_eq!(busy, ["server-1", "server-2"].iter().map(|s| String::from(*s)).collect());
+    }
+
+    #[tokio::test]
+    async fn allocate_server_skips_servers_with_players_online() {
+        let server = |id: &str| ServerConfig { id: String::from(id), url: format!("{}:27015", id), rcon_address: None };
+        let provider = FakeProvider { busy: vec![String::from("a")], ..Default::default() };
+        let allocated = allocate_server(&provider, vec![server("a"), server("b")]).await.unwrap();
+        assert_eq!(allocated.id, "b");
+        assert!(allocate_server(&provider, vec![server("a")]).await.is_err());
+    }
+
+    #[test]
+    fn busy_servers_skip_matches_past_the_timeout() {
+        let mut live_matches = HashMap::new();
+        live_matches.insert(String::from("match-1"), live_match("server-1", 1000));
+        let now = 1000 + MATCH_TIMEOUT.as_secs() as i64;
+        assert!(busy_servers(&live_matches, &HashMap::new(), now).is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+use serde::Deserialize;
+use serenity::async_trait;
+
+use crate::{DathostConfig, ServerConfig};
+use crate::db::MapEntry;
+use crate::game_server::{GameServerProvider, MatchSettings, ServerStatus};
+
+const DATHOST_API_URL: &str = "https://dathost.net/api/0.1";
+
+pub(crate) struct DathostProvider {
+    username: String,
+    password: String,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct DathostMatch {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct DathostGameServer {
+    on: bool,
+    players_online: u64,
+}
+
+impl DathostProvider {
+    pub(crate) fn new(config: &DathostConfig) -> DathostProvider {
+        DathostProvider {
+            username: String::from(&config.username),
+            password: String::from(&config.password),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn password(&self) -> Option<String> {
+        Some(String::from(&self.password))
+    }
+}
+
+#[async_trait]
+impl GameServerProvider for DathostProvider {
+    async fn set_start_map(&self, server: &ServerConfig, map: &MapEntry) -> Result<(), String> {
+        let update_map_url = format!("{}/game-servers/{}", DATHOST_API_URL, &server.id);
+        // workshop maps need a workshop auth key to be set on the server in the DatHost control panel
+        let form = match map.workshop_id {
+            Some(workshop_id) => [("csgo_settings.maps_source", String::from("workshop_single_map")),
+                ("csgo_settings.workshop_start_map_id", workshop_id.to_string())],
+            None => [("csgo_settings.maps_source", String::from("mapgroup")),
+                ("csgo_settings.mapgroup_start_map", String::from(&map.map_name))],
+        };
+        let resp = self.client
+            .put(&update_map_url)
+            .form(&form)
+            .basic_auth(&self.username, self.password())
+            .send()
+            .await
+            .map_err(|e| format!("change map request failed: {}", e))?;
+        println!("Change map response - {:#?}", resp.status());
+        if !resp.status().is_success() {
+            return Err(format!("change map PUT response code: {}", resp.status().as_str()));
+        }
+        Ok(())
+    }
+
+    async fn start_match(&self, server: &ServerConfig, settings: &MatchSettings) -> Result<Option<String>, String> {
+        let start_match_url = format!("{}/matches", DATHOST_API_URL);
+        let match_end_url = settings.match_end_url.clone().unwrap_or_default();
+        let auth_str = settings.webhook_authorization_header.clone().unwrap_or_default();
+        println!("match_end_webhook_url:'{}'", &match_end_url);
+        println!("game_server_id:'{}'", &server.id);
+        let resp = self.client
+            .post(&start_match_url)
+            .form(&[("game_server_id", &server.id),
+                ("team1_steam_ids", &settings.team1_steam_ids.join(",")),
+                ("team2_steam_ids", &settings.team2_steam_ids.join(",")),
+                ("enable_pause", &String::from("true")),
+                ("webhook_authorization_header", &auth_str),
+                ("match_end_webhook_url", &match_end_url)])
+            .basic_auth(&self.username, self.password())
+            .send()
+            .await
+            .map_err(|e| format!("match POST request failed: {}", e))?;
+        println!("Start match response code - {}", &resp.status());
+        if !resp.status().is_success() {
+            return Err(format!("match POST response code: {}", resp.status().as_str()));
+        }
+        match resp.json::<DathostMatch>().await {
+            Ok(dathost_match) => Ok(Some(dathost_match.id)),
+            Err(why) => {
+                eprintln!("Unable to read match id from DatHost response: {:?}", why);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn send_console(&self, server: &ServerConfig, line: &str) -> Result<(), String> {
+        let send_command_url = format!("{}/game-servers/{}/console", DATHOST_API_URL, &server.id);
+        let resp = self.client
+            .post(&send_command_url)
+            .form(&[("line", line)])
+            .basic_auth(&self.username, self.password())
+            .send()
+            .await
+            .map_err(|e| format!("console POST request failed: {}", e))?;
+        if !resp.status().is_success() {
+            return Err(format!("console POST response code: {}", resp.status().as_str()));
+        }
+        Ok(())
+    }
+
+    async fn status(&self, server: &ServerConfig) -> Result<ServerStatus, String> {
+        let server_url = format!("{}/game-servers/{}", DATHOST_API_URL, &server.id);
+        let resp = self.client
+            .get(&server_url)
+            .basic_auth(&self.username, self.password())
+            .send()
+            .await
+            .map_err(|e| format!("game server GET request failed: {}", e))?;
+        if !resp.status().is_success() {
+            return Err(format!("game server GET response code: {}", resp.status().as_str()));
+        }
+        let game_server: DathostGameServer = resp.json()
+            .await
+            .map_err(|e| format!("unable to read game server status: {}", e))?;
+        Ok(ServerStatus { on: game_server.on, players_online: game_server.players_online })
+    }
+
+    fn reports_match_end(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rusqlite::{Connection, params, Result};
+
+const DB_FILE: &str = "scrimbot.db";
+
+/// Schema migrations, applied in order. `PRAGMA user_version` holds the number already applied,
+/// so only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE players (
+        discord_id INTEGER PRIMARY KEY,
+        steam_id TEXT NOT NULL
+    );
+    CREATE TABLE team_names (
+        discord_id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL
+    );
+    CREATE TABLE maps (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE matches (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        dathost_match_id TEXT,
+        team1_name TEXT NOT NULL,
+        team2_name TEXT NOT NULL,
+        team1_score INTEGER NOT NULL,
+        team2_score INTEGER NOT NULL,
+        finished_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );",
+    // matches are recorded on launch, team 1 is the team that started T
+    "CREATE TABLE matches_v2 (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        dathost_match_id TEXT,
+        map TEXT NOT NULL DEFAULT '',
+        team1_name TEXT NOT NULL,
+        team2_name TEXT NOT NULL,
+        team1_captain_id INTEGER,
+        team2_captain_id INTEGER,
+        team1_score INTEGER,
+        team2_score INTEGER,
+        started_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        finished_at TEXT
+    );
+    INSERT INTO matches_v2 (id, dathost_match_id, team1_name, team2_name, team1_score, team2_score, started_at, finished_at)
+        SELECT id, dathost_match_id, team1_name, team2_name, team1_score, team2_score, finished_at, finished_at FROM matches;
+    DROP TABLE matches;
+    ALTER TABLE matches_v2 RENAME TO matches;
+    CREATE TABLE match_players (
+        match_id INTEGER NOT NULL REFERENCES matches (id),
+        team INTEGER NOT NULL,
+        discord_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        steam_id TEXT NOT NULL
+    );
+    CREATE INDEX match_players_match_id ON match_players (match_id);",
+    // `name` is now the display name, `map_name` what the server loads
+    "ALTER TABLE maps ADD COLUMN map_name TEXT NOT NULL DEFAULT '';
+    UPDATE maps SET map_name = name;
+    ALTER TABLE maps ADD COLUMN workshop_id INTEGER;
+    ALTER TABLE maps ADD COLUMN enabled INTEGER NOT NULL DEFAULT 1;",
+];
+
+/// A map in the pool. `name` is what players see and vote on, `map_name` is what the server loads
+/// unless the map comes from the Steam Workshop.
+#[derive(Clone)]
+pub(crate) struct MapEntry {
+    pub(crate) name: String,
+    pub(crate) map_name: String,
+    pub(crate) workshop_id: Option<u64>,
+    pub(crate) enabled: bool,
+}
+
+impl MapEntry {
+    pub(crate) fn new(map_name: &str) -> MapEntry {
+        MapEntry {
+            name: String::from(map_name),
+            map_name: String::from(map_name),
+            workshop_id: None,
+            enabled: true,
+        }
+    }
+}
+
+/// A launched match. Team 1 started on the T side, team 2 on the CT side.
+pub(crate) struct MatchRecord {
+    pub(crate) id: i64,
+    pub(crate) dathost_match_id: Option<String>,
+    pub(crate) map: String,
+    pub(crate) team1_name: String,
+    pub(crate) team2_name: String,
+    pub(crate) team1_captain_id: Option<u64>,
+    pub(crate) team2_captain_id: Option<u64>,
+    pub(crate) team1_score: Option<i64>,
+    pub(crate) team2_score: Option<i64>,
+    pub(crate) started_at: String,
+    pub(crate) finished_at: Option<String>,
+    pub(crate) players: Vec<MatchPlayer>,
+}
+
+pub(crate) struct MatchPlayer {
+    pub(crate) team: u8,
+    pub(crate) discord_id: u64,
+    pub(crate) name: String,
+    pub(crate) steam_id: String,
+}
+
+pub(crate) struct Database {
+    conn: Mutex<Connection>,
+}
+
+impl Database {
+    /// Opens (or creates) the database and brings the schema up to date.
+    /// The legacy JSON files are imported the first time the database is created.
+    pub(crate) fn open() -> Result<Database> {
+        let conn = Connection::open(DB_FILE)?;
+        let previous_version = migrate(&conn)?;
+        let db = Database { conn: Mutex::new(conn) };
+        if previous_version == 0 {
+            db.import_json_files()?;
+        }
+        Ok(db)
+    }
+
+    pub(crate) fn steam_ids(&self) -> Result<HashMap<u64, String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT discord_id, steam_id FROM players")?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    pub(crate) fn set_steam_id(&self, discord_id: u64, steam_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("INSERT OR REPLACE INTO players (discord_id, steam_id) VALUES (?1, ?2)",
+                     params![discord_id as i64, steam_id])?;
+        Ok(())
+    }
+
+    pub(crate) fn team_names(&self) -> Result<HashMap<u64, String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT discord_id, name FROM team_names")?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    pub(crate) fn set_team_name(&self, discord_id: u64, name: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("INSERT OR REPLACE INTO team_names (discord_id, name) VALUES (?1, ?2)",
+                     params![discord_id as i64, name])?;
+        Ok(())
+    }
+
+    pub(crate) fn maps(&self) -> Result<Vec<MapEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT name, map_name, workshop_id, enabled FROM maps ORDER BY id")?;
+        let rows = stmt.query_map(params![], |row| Ok(MapEntry {
+            name: row.get(0)?,
+            map_name: row.get(1)?,
+            workshop_id: row.get::<_, Option<i64>>(2)?.map(|id| id as u64),
+            enabled: row.get(3)?,
+        }))?;
+        rows.collect()
+    }
+
+    pub(crate) fn add_map(&self, map: &MapEntry) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("INSERT OR IGNORE INTO maps (name, map_name, workshop_id, enabled) VALUES (?1, ?2, ?3, ?4)",
+                     params![map.name, map.map_name, map.workshop_id.map(|id| id as i64), map.enabled])?;
+        Ok(())
+    }
+
+    pub(crate) fn set_map_enabled(&self, name: &str, enabled: bool) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("UPDATE maps SET enabled = ?2 WHERE name = ?1", params![name, enabled])?;
+        Ok(())
+    }
+
+    pub(crate) fn remove_map(&self, name: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM maps WHERE name = ?1", params![name])?;
+        Ok(())
+    }
+
+    /// Records a launched match and its rosters, returning the new match id
+    pub(crate) fn record_match(&self, record: &MatchRecord) -> Result<i64> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("INSERT INTO matches (dathost_match_id, map, team1_name, team2_name, team1_captain_id, team2_captain_id) \
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                   params![record.dathost_match_id, record.map, record.team1_name, record.team2_name,
+                       record.team1_captain_id.map(|id| id as i64), record.team2_captain_id.map(|id| id as i64)])?;
+        let match_id = tx.last_insert_rowid();
+        for player in &record.players {
+            tx.execute("INSERT INTO match_players (match_id, team, discord_id, name, steam_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+                       params![match_id, player.team, player.discord_id as i64, player.name, player.steam_id])?;
+        }
+        tx.commit()?;
+        Ok(match_id)
+    }
+
+    /// Sets the final score of a match launched on DatHost, recording a bare result if the match is unknown
+    pub(crate) fn record_match_result(&self, dathost_match_id: &str, team1_name: &str, team2_name: &str, team1_score: u64, team2_score: u64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute("UPDATE matches SET team1_score = ?2, team2_score = ?3, finished_at = CURRENT_TIMESTAMP \
+                                    WHERE dathost_match_id = ?1",
+                                   params![dathost_match_id, team1_score as i64, team2_score as i64])?;
+        if updated == 0 {
+            conn.execute("INSERT INTO matches (dathost_match_id, team1_name, team2_name, team1_score, team2_score, finished_at) \
+                          VALUES (?1, ?2, ?3, ?4, ?5, CURRENT_TIMESTAMP)",
+                         params![dathost_match_id, team1_name, team2_name, team1_score as i64, team2_score as i64])?;
+        }
+        Ok(())
+    }
+
+    /// The most recently launched matches, newest first. Rosters are not loaded.
+    pub(crate) fn recent_matches(&self, limit: u32) -> Result<Vec<MatchRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("{} ORDER BY id DESC LIMIT ?1", SELECT_MATCH))?;
+        let rows = stmt.query_map(params![limit], map_match_row)?;
+        rows.collect()
+    }
+
+    pub(crate) fn find_match(&self, id: i64) -> Result<Option<MatchRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("{} WHERE id = ?1", SELECT_MATCH))?;
+        let mut rows = stmt.query_map(params![id], map_match_row)?;
+        let mut record = match rows.next() {
+            Some(record) => record?,
+            None => return Ok(None),
+        };
+        let mut stmt = conn.prepare("SELECT team, discord_id, name, steam_id FROM match_players WHERE match_id = ?1 ORDER BY rowid")?;
+        let players = stmt.query_map(params![id], |row| Ok(MatchPlayer {
+            team: row.get(0)?,
+            discord_id: row.get::<_, i64>(1)? as u64,
+            name: row.get(2)?,
+            steam_id: row.get(3)?,
+        }))?;
+        record.players = players.collect::<Result<Vec<_>>>()?;
+        Ok(Some(record))
+    }
+
+    /// The maps of the most recently launched matches, newest first
+    pub(crate) fn recent_maps(&self, limit: usize) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT map FROM matches WHERE map != '' ORDER BY id DESC LIMIT ?1")?;
+        let rows = stmt.query_map(params![limit as i64], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Wins and finished matches played per discord user, from the match history
+    pub(crate) fn win_loss(&self) -> Result<HashMap<u64, (u32, u32)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT p.discord_id, \
+                                            SUM(CASE WHEN (p.team = 1 AND m.team1_score > m.team2_score) \
+                                                       OR (p.team = 2 AND m.team2_score > m.team1_score) THEN 1 ELSE 0 END), \
+                                            COUNT(*) \
+                                     FROM match_players p JOIN matches m ON m.id = p.match_id \
+                                     WHERE m.team1_score IS NOT NULL AND m.team2_score IS NOT NULL \
+                                     GROUP BY p.discord_id")?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get::<_, i64>(0)? as u64, (row.get(1)?, row.get(2)?))))?;
+        rows.collect()
+    }
+
+    /// One-time import of `steam-ids.json`, `teamnames.json` and `maps.json` from before the database existed
+    fn import_json_files(&self) -> Result<()> {
+        let steam_ids: HashMap<u64, String> = read_json_file("steam-ids.json").unwrap_or_default();
+        for (discord_id, steam_id) in &steam_ids {
+            self.set_steam_id(*discord_id, steam_id)?;
+        }
+        let team_names: HashMap<u64, String> = read_json_file("teamnames.json").unwrap_or_default();
+        for (discord_id, name) in &team_names {
+            self.set_team_name(*discord_id, name)?;
+        }
+        let maps: Vec<String> = read_json_file("maps.json").unwrap_or_default();
+        for map in &maps {
+            self.add_map(&MapEntry::new(map))?;
+        }
+        if !steam_ids.is_empty() || !team_names.is_empty() || !maps.is_empty() {
+            println!("Imported {} steamID(s), {} team name(s) and {} map(s) from JSON files into {}",
+                     steam_ids.len(), team_names.len(), maps.len(), DB_FILE);
+        }
+        Ok(())
+    }
+}
+
+const SELECT_MATCH: &str = "SELECT id, dathost_match_id, map, team1_name, team2_name, team1_captain_id, team2_captain_id, \
+                            team1_score, team2_score, started_at, finished_at FROM matches";
+
+fn map_match_row(row: &rusqlite::Row) -> Result<MatchRecord> {
+    Ok(MatchRecord {
+        id: row.get(0)?,
+        dathost_match_id: row.get(1)?,
+        map: row.get(2)?,
+        team1_name: row.get(3)?,
+        team2_name: row.get(4)?,
+        team1_captain_id: row.get::<_, Option<i64>>(5)?.map(|id| id as u64),
+        team2_captain_id: row.get::<_, Option<i64>>(6)?.map(|id| id as u64),
+        team1_score: row.get(7)?,
+        team2_score: row.get(8)?,
+        started_at: row.get(9)?,
+        finished_at: row.get(10)?,
+        players: Vec::new(),
+    })
+}
+
+/// Applies any pending migrations, returning the schema version the database was at before
+fn migrate(conn: &Connection) -> Result<usize> {
+    let version: i64 = conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+    let version = version as usize;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        println!("Applying database migration {}", i + 1);
+        conn.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;", migration, i + 1))?;
+    }
+    Ok(version)
+}
+
+fn read_json_file<T: serde::de::DeserializeOwned>(path: &str) -> Option<T> {
+    let json_str = std::fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&json_str) {
+        Ok(json) => Some(json),
+        Err(why) => {
+            eprintln!("Unable to import {}: {:?}", path, why);
+            None
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use serenity::async_trait;
+
+use crate::ServerConfig;
+use crate::db::MapEntry;
+
+/// Everything a provider needs to know to start a match on a game server.
+/// Team 1 starts as T and team 2 starts as CT.
+pub(crate) struct MatchSettings {
+    pub(crate) team1_steam_ids: Vec<String>,
+    pub(crate) team2_steam_ids: Vec<String>,
+    pub(crate) match_end_url: Option<String>,
+    pub(crate) webhook_authorization_header: Option<String>,
+}
+
+pub(crate) struct ServerStatus {
+    pub(crate) on: bool,
+    pub(crate) players_online: u64,
+}
+
+/// A host that can run matches for the bot, i.e. DatHost.
+/// Errors are returned as human readable strings so they can be posted to the channel as-is.
+#[async_trait]
+pub(crate) trait GameServerProvider: Send + Sync {
+    /// Sets the map the next match will be played on, loading it from the Steam Workshop if it has a workshop id
+    async fn set_start_map(&self, server: &ServerConfig, map: &MapEntry) -> Result<(), String>;
+
+    /// Starts a match, returning the provider's match id if it has one
+    async fn start_match(&self, server: &ServerConfig, settings: &MatchSettings) -> Result<Option<String>, String>;
+
+    /// Sends a single line to the server console, i.e. `mp_teamname_1 Foo`
+    async fn send_console(&self, server: &ServerConfig, line: &str) -> Result<(), String>;
+
+    async fn status(&self, server: &ServerConfig) -> Result<ServerStatus, String>;
+
+    /// Whether started matches get a match id and a match end webhook, which a BO3 series needs to move on
+    fn reports_match_end(&self) -> bool;
+}
+
+/// Loads the map, starts the match and names the teams. Failing to change the map or to set a team name
+/// is only logged, the match is still started. Returns the result of starting the match.
+pub(crate) async fn launch_match(provider: &dyn GameServerProvider, server: &ServerConfig, map: &MapEntry,
+                                 settings: &MatchSettings, team_ct_name: &str, team_t_name: &str) -> Result<Option<String>, String> {
+    if let Err(why) = provider.set_start_map(server, map).await {
+        eprintln!("Error changing map: {}", why);
+    }
+    let start_result = provider.start_match(server, settings).await;
+    // mp_teamname_1 names the team starting CT, unlike the provider's team 1
+    if let Err(why) = provider.send_console(server, &format!("mp_teamname_1 {}", team_ct_name)).await {
+        eprintln!("Error setting team name 1: {}", why);
+    }
+    if let Err(why) = provider.send_console(server, &format!("mp_teamname_2 {}", team_t_name)).await {
+        eprintln!("Error setting team name 2: {}", why);
+    }
+    start_result
+}
+
+/// A provider that records the calls it gets instead of talking to a game server
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::sync::Mutex;
+
+    use serenity::async_trait;
+
+    use crate::ServerConfig;
+    use crate::db::MapEntry;
+    use crate::game_server::{GameServerProvider, MatchSettings, ServerStatus};
+
+    #[derive(Default)]
+    pub(crate) struct FakeProvider {
+        pub(crate) calls: Mutex<Vec<String>>,
+        pub(crate) fail_map: bool,
+        pub(crate) fail_start: bool,
+        /// The ids of the servers that report a player online
+        pub(crate) busy: Vec<String>,
+    }
+
+    impl FakeProvider {
+        fn record(&self, server: &ServerConfig, call: String) {
+            self.calls.lock().unwrap().push(format!("{}: {}", &server.id, call));
+        }
+    }
+
+    #[async_trait]
+    impl GameServerProvider for FakeProvider {
+        async fn set_start_map(&self, server: &ServerConfig, map: &MapEntry) -> Result<(), String> {
+            self.record(server, format!("map {}", &map.map_name));
+            if self.fail_map { Err(String::from("map failed")) } else { Ok(()) }
+        }
+
+        async fn start_match(&self, server: &ServerConfig, settings: &MatchSettings) -> Result<Option<String>, String> {
+            self.record(server, format!("start {} vs {}", settings.team1_steam_ids.join(","), settings.team2_steam_ids.join(",")));
+            if self.fail_start { Err(String::from("start failed")) } else { Ok(Some(String::from("match-1"))) }
+        }
+
+        async fn send_console(&self, server: &ServerConfig, line: &str) -> Result<(), String> {
+            self.record(server, String::from(line));
+            Ok(())
+        }
+
+        async fn status(&self, server: &ServerConfig) -> Result<ServerStatus, String> {
+            self.record(server, String::from("status"));
+            Ok(ServerStatus { on: true, players_online: if self.busy.contains(&server.id) { 1 } else { 0 } })
+        }
+
+        fn reports_match_end(&self) -> bool {
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::fake::FakeProvider;
+
+    fn server() -> ServerConfig {
+        ServerConfig { id: String::from("server-1"), url: String::from("127.0.0.1:27015"), rcon_address: None }
+    }
+
+    fn settings() -> MatchSettings {
+        MatchSettings {
+            team1_steam_ids: vec![String::from("STEAM_1:0:1")],
+            team2_steam_ids: vec![String::from("STEAM_1:0:2")],
+            match_end_url: None,
+            webhook_authorization_header: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn launch_match_sets_map_starts_and_names_teams() {
+        let provider = FakeProvider::default();
+        let result = launch_match(&provider, &server(), &MapEntry::new("de_dust2"), &settings(), "Team CT", "Team T").await;
+        assert_eq!(result, Ok(Some(String::from("match-1"))));
+        assert_eq!(*provider.calls.lock().unwrap(), vec![
+            "server-1: map de_dust2",
+            "server-1: start STEAM_1:0:1 vs STEAM_1:0:2",
+            "server-1: mp_teamname_1 Team CT",
+            "server-1: mp_teamname_2 Team T",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn launch_match_starts_even_if_the_map_change_fails() {
+        let provider = FakeProvider { fail_map: true, ..Default::default() };
+        let result = launch_match(&provider, &server(), &MapEntry::new("de_dust2"), &settings(), "Team CT", "Team T").await;
+        assert!(result.is_ok());
+        assert!(provider.calls.lock().unwrap().iter().any(|c| c.contains("start")));
+    }
+
+    #[tokio::test]
+    async fn launch_match_returns_start_errors() {
+        let provider = FakeProvider { fail_start: true, ..Default::default() };
+        let result = launch_match(&provider, &server(), &MapEntry::new("de_dust2"), &settings(), "Team CT", "Team T").await;
+        assert_eq!(result, Err(String::from("start failed")));
+    }
+}
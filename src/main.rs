@@ -1,6 +1,8 @@
 use core::time::Duration as CoreDuration;
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use async_std::task;
 use chrono::{Datelike, DateTime, Duration as ChronoDuration, Local, TimeZone};
@@ -9,20 +11,49 @@ use serenity::async_trait;
 use serenity::Client;
 use serenity::client::Context;
 use serenity::framework::standard::StandardFramework;
+use serenity::cache::Cache;
 use serenity::model::channel::Message;
+use serenity::model::guild::Guild;
+use serenity::model::id::{ChannelId, GuildId};
+use serenity::model::interactions::{Interaction, InteractionResponseType};
 use serenity::model::prelude::Ready;
 use serenity::model::user::User;
 use serenity::prelude::{EventHandler, TypeMapKey};
+
+use crate::dathost::DathostProvider;
+use crate::db::{Database, MapEntry};
+use crate::game_server::GameServerProvider;
+use crate::rcon::RconProvider;
+
 mod bot_service;
+mod dathost;
+mod db;
+mod game_server;
+mod persistence;
+mod rcon;
+mod slash_commands;
+mod webhook;
 
 #[derive(Serialize, Deserialize)]
 struct Config {
     server: ServerConfig,
-    dathost: DathostConfig,
+    servers: Option<Vec<ServerConfig>>,
+    dathost: Option<DathostConfig>,
+    rcon: Option<RconConfig>,
     discord: DiscordConfig,
     post_setup_msg: Option<String>,
     autoclear_hour: Option<u32>,
-    scrimbot_api_config: ScrimbotApiConfig
+    team_size: Option<usize>,
+    pick_order: Option<String>,
+    map_vote_time: Option<u64>,
+    map_votes_per_player: Option<usize>,
+    exclude_recent_maps: Option<usize>,
+    map_vote_size: Option<usize>,
+    ready_timeout: Option<u64>,
+    queue_pop: Option<QueuePopConfig>,
+    scrimbot_api_config: ScrimbotApiConfig,
+    webhook: Option<WebhookConfig>,
+    lobbies: Option<Vec<LobbyConfig>>,
 }
 
 
@@ -34,9 +65,18 @@ struct ScrimbotApiConfig {
 }
 
 #[derive(Serialize, Deserialize)]
+struct LobbyConfig {
+    channel_id: u64,
+    name: Option<String>,
+    server: Option<ServerConfig>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct ServerConfig {
     id: String,
     url: String,
+    /// RCON host:port when it differs from `url`, only used by the RCON provider
+    rcon_address: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -46,9 +86,31 @@ struct DathostConfig {
     match_end_url: Option<String>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct RconConfig {
+    address: Option<String>,
+    password: String,
+    match_config: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct QueuePopConfig {
+    dm_players: Option<bool>,
+    auto_start_delay: Option<u64>,
+    auto_start_options: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WebhookConfig {
+    bind_address: String,
+    channel_id: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct DiscordConfig {
     token: String,
+    application_id: Option<u64>,
+    text_commands: Option<bool>,
     admin_role_id: u64,
     team_a_channel_id: Option<u64>,
     team_b_channel_id: Option<u64>,
@@ -59,11 +121,40 @@ struct DiscordConfig {
     assign_role_id: Option<u64>,
 }
 
-#[derive(PartialEq)]
+/// A command invocation, either a text command or a slash command rebuilt as one.
+/// Mirrors the parts of `Message` the command handlers use.
+struct CommandMessage {
+    author: User,
+    channel_id: ChannelId,
+    guild_id: Option<GuildId>,
+    content: String,
+    mentions: Vec<User>,
+}
+
+impl CommandMessage {
+    async fn guild(&self, cache: impl AsRef<Cache>) -> Option<Guild> {
+        cache.as_ref().guild(self.guild_id?).await
+    }
+}
+
+impl From<&Message> for CommandMessage {
+    fn from(msg: &Message) -> CommandMessage {
+        CommandMessage {
+            author: msg.author.clone(),
+            channel_id: msg.channel_id,
+            guild_id: msg.guild_id,
+            content: msg.content.clone(),
+            mentions: msg.mentions.clone(),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Serialize, Deserialize)]
 struct StateContainer {
     state: State,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 struct Draft {
     captain_a: Option<User>,
     captain_b: Option<User>,
@@ -71,41 +162,159 @@ struct Draft {
     team_b: Vec<User>,
     team_b_start_side: String,
     current_picker: Option<User>,
+    #[serde(default)]
+    map: String,
+    #[serde(default)]
+    draft_message_id: Option<u64>,
+    #[serde(default)]
+    pick_index: usize,
+}
+
+/// A queue and everything needed to set up one scrim from it, there is one per lobby channel
+#[derive(Clone, Serialize, Deserialize)]
+struct Lobby {
+    user_queue: Vec<User>,
+    ready_queue: Vec<User>,
+    draft: Draft,
+    state: StateContainer,
+    queue_messages: HashMap<u64, String>,
+    #[serde(default)]
+    veto: Option<Veto>,
+    #[serde(default)]
+    series: Option<Series>,
+    /// Players who joined once the queue was full, in the order they joined
+    #[serde(default)]
+    waitlist: Vec<User>,
+    /// Unix timestamp the ready check ends at when `ready_timeout` is configured
+    #[serde(default)]
+    ready_deadline: Option<i64>,
+    /// The ready check summary, edited whenever a player readies up
+    #[serde(default)]
+    ready_message_id: Option<u64>,
+    /// The server the lobby's match is being launched on, or its series is played on, so no other lobby picks it
+    #[serde(default)]
+    server_id: Option<String>,
+}
+
+/// A map veto between the captains, set up by `.start veto` and run once the teams are complete
+#[derive(Clone, Serialize, Deserialize)]
+struct Veto {
+    remaining: Vec<String>,
+    picked: Vec<String>,
+    best_of_three: bool,
+    map_count: usize,
+    step: usize,
+    message_id: Option<u64>,
+    /// The captain whose pick (or last ban, for the decider) put each of the `picked` maps in the series
+    #[serde(default)]
+    picked_by: Vec<u64>,
+}
+
+/// A best of three series played map by map once the veto is done, each map is launched after the previous one's
+/// match end webhook
+#[derive(Clone, Serialize, Deserialize)]
+struct Series {
+    maps: Vec<String>,
+    /// The captain picking the starting side of each map, always the one who didn't pick it
+    side_pickers: Vec<u64>,
+    map_index: usize,
+    score_a: u32,
+    score_b: u32,
+    /// The provider's match id of the map being played, only its match end webhook moves the series forward
+    #[serde(default)]
+    match_id: Option<String>,
 }
 
-#[derive(PartialEq)]
+impl Veto {
+    fn new(maps: &[String], best_of_three: bool) -> Veto {
+        Veto {
+            remaining: maps.to_vec(),
+            picked: Vec::new(),
+            best_of_three,
+            map_count: maps.len(),
+            step: 0,
+            message_id: None,
+            picked_by: Vec::new(),
+        }
+    }
+}
+
+impl Lobby {
+    fn new() -> Lobby {
+        Lobby {
+            user_queue: Vec::new(),
+            ready_queue: Vec::new(),
+            draft: Draft {
+                captain_a: None,
+                captain_b: None,
+                current_picker: None,
+                team_a: Vec::new(),
+                team_b: Vec::new(),
+                team_b_start_side: String::from(""),
+                map: String::new(),
+                draft_message_id: None,
+                pick_index: 0,
+            },
+            state: StateContainer { state: State::Queue },
+            queue_messages: HashMap::new(),
+            veto: None,
+            series: None,
+            waitlist: Vec::new(),
+            ready_deadline: None,
+            ready_message_id: None,
+            server_id: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct LiveMatch {
+    channel_id: u64,
+    team1_name: String,
+    team2_name: String,
+    /// The server the match is played on, which is busy until the match end webhook arrives
+    #[serde(default)]
+    server_id: Option<String>,
+    /// Unix timestamp the match was launched at
+    #[serde(default)]
+    started_at: i64,
+}
+
+#[derive(PartialEq, Clone, Serialize, Deserialize)]
 enum State {
     Queue,
     MapPick,
+    RandomTeams,
     CaptainPick,
     Draft,
+    MapVeto,
     SidePick,
     Ready,
+    /// A map of a series is being played, the next one is set up when its match end webhook arrives
+    Live,
 }
 
-struct Handler;
-
-struct UserQueue;
+struct Handler {
+    timeouts_resumed: AtomicBool,
+}
 
-struct ReadyQueue;
+struct Lobbies;
 
 struct SteamIdCache;
 
 struct TeamNameCache;
 
-struct BotState;
-
 struct Maps;
 
-struct QueueMessages;
+struct GameServer;
 
+struct LiveMatches;
 
-impl TypeMapKey for UserQueue {
-    type Value = Vec<User>;
-}
+struct Db;
 
-impl TypeMapKey for ReadyQueue {
-    type Value = Vec<User>;
+
+impl TypeMapKey for Lobbies {
+    type Value = HashMap<u64, Lobby>;
 }
 
 impl TypeMapKey for Config {
@@ -120,20 +329,20 @@ impl TypeMapKey for TeamNameCache{
     type Value = HashMap<u64, String>;
 }
 
-impl TypeMapKey for BotState {
-    type Value = StateContainer;
+impl TypeMapKey for Maps {
+    type Value = Vec<MapEntry>;
 }
 
-impl TypeMapKey for Maps {
-    type Value = Vec<String>;
+impl TypeMapKey for GameServer {
+    type Value = Arc<dyn GameServerProvider>;
 }
 
-impl TypeMapKey for Draft {
-    type Value = Draft;
+impl TypeMapKey for Db {
+    type Value = Database;
 }
 
-impl TypeMapKey for QueueMessages {
-    type Value = HashMap<u64, String>;
+impl TypeMapKey for LiveMatches {
+    type Value = HashMap<String, LiveMatch>;
 }
 
 enum Command {
@@ -148,9 +357,13 @@ enum Command {
     ADDMAP,
     CANCEL,
     REMOVEMAP,
+    ENABLEMAP,
+    DISABLEMAP,
     KICK,
+    SUB,
     CAPTAIN,
     PICK,
+    BAN,
     READY,
     UNREADY,
     CT,
@@ -158,6 +371,8 @@ enum Command {
     READYLIST,
     RECOVERQUEUE,
     CLEAR,
+    HISTORY,
+    MATCH,
     HELP,
     UNKNOWN,
 }
@@ -166,29 +381,35 @@ impl FromStr for Command {
     type Err = ();
     fn from_str(input: &str) -> Result<Command, Self::Err> {
         match input {
-            _ if ".join".starts_with(input) => Ok(Command::JOIN),
-            _ if ".leave".starts_with(input) => Ok(Command::LEAVE),
-            _ if ".queue".starts_with(input) => Ok(Command::QUEUE),
+            ".join" => Ok(Command::JOIN),
+            ".leave" => Ok(Command::LEAVE),
+            ".queue" | ".list" => Ok(Command::QUEUE),
             ".start" => Ok(Command::START),
             ".steamid" => Ok(Command::STEAMID),
             ".maps" => Ok(Command::MAPS),
             ".stats" => Ok(Command::STATS),
             ".teamname" => Ok(Command::TEAMNAME),
             ".kick" => Ok(Command::KICK),
+            ".sub" => Ok(Command::SUB),
             ".addmap" => Ok(Command::ADDMAP),
             ".cancel" => Ok(Command::CANCEL),
             ".captain" => Ok(Command::CAPTAIN),
             ".pick" => Ok(Command::PICK),
-            _ if ".ready".starts_with(input) => Ok(Command::READY),
+            ".ban" => Ok(Command::BAN),
+            ".ready" => Ok(Command::READY),
             ".gaben" => Ok(Command::READY),
             ".unready" => Ok(Command::UNREADY),
             ".ct" => Ok(Command::CT),
             ".t" => Ok(Command::T),
             ".readylist" => Ok(Command::READYLIST),
             ".removemap" => Ok(Command::REMOVEMAP),
+            ".enablemap" => Ok(Command::ENABLEMAP),
+            ".disablemap" => Ok(Command::DISABLEMAP),
             ".recoverqueue" => Ok(Command::RECOVERQUEUE),
             ".clear" => Ok(Command::CLEAR),
-            _ if ".help".starts_with(input) => Ok(Command::HELP),
+            ".history" => Ok(Command::HISTORY),
+            ".match" => Ok(Command::MATCH),
+            ".help" => Ok(Command::HELP),
             _ => Err(()),
         }
     }
@@ -199,40 +420,51 @@ impl EventHandler for Handler {
     async fn message(&self, context: Context, msg: Message) {
         if msg.author.bot { return; }
         if !msg.content.starts_with('.') { return; }
+        if !text_commands_enabled(&context).await { return; }
         let command = Command::from_str(&msg.content.to_lowercase()
             .trim()
             .split(' ')
             .take(1)
             .collect::<Vec<_>>()[0])
             .unwrap_or(Command::UNKNOWN);
-        match command {
-            Command::JOIN => bot_service::handle_join(&context, &msg, &msg.author).await,
-            Command::LEAVE => bot_service::handle_leave(context, msg).await,
-            Command::QUEUE => bot_service::handle_list(context, msg).await,
-            Command::START => bot_service::handle_start(context, msg).await,
-            Command::STEAMID => bot_service::handle_steam_id(context, msg).await,
-            Command::MAPS => bot_service::handle_map_list(context, msg).await,
-            Command::STATS => bot_service::handle_stats(context, msg).await,
-            Command::TEAMNAME => bot_service::handle_teamname(context, msg).await,
-            Command::KICK => bot_service::handle_kick(context, msg).await,
-            Command::CANCEL => bot_service::handle_cancel(context, msg).await,
-            Command::ADDMAP => bot_service::handle_add_map(context, msg).await,
-            Command::REMOVEMAP => bot_service::handle_remove_map(context, msg).await,
-            Command::CAPTAIN => bot_service::handle_captain(context, msg).await,
-            Command::PICK => bot_service::handle_pick(context, msg).await,
-            Command::READY => bot_service::handle_ready(context, msg).await,
-            Command::UNREADY => bot_service::handle_unready(context, msg).await,
-            Command::CT => bot_service::handle_ct_option(context, msg).await,
-            Command::T => bot_service::handle_t_option(context, msg).await,
-            Command::READYLIST => bot_service::handle_ready_list(context, msg).await,
-            Command::RECOVERQUEUE => bot_service::handle_recover_queue(context, msg).await,
-            Command::CLEAR => bot_service::handle_clear(context, msg).await,
-            Command::HELP => bot_service::handle_help(context, msg).await,
-            Command::UNKNOWN => bot_service::handle_unknown(context, msg).await,
+        dispatch(command, context, CommandMessage::from(&msg)).await;
+    }
+    async fn interaction_create(&self, context: Context, interaction: Interaction) {
+        let interaction = match interaction {
+            Interaction::ApplicationCommand(interaction) => interaction,
+            Interaction::MessageComponent(interaction) => return bot_service::handle_button(context, interaction).await,
+            _ => return,
+        };
+        let (command, msg) = match slash_commands::to_command_message(&interaction) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        // echo the equivalent text command so the channel can see what was done
+        if let Err(why) = interaction.create_interaction_response(&context.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|data| data.content(&msg.content))
+        }).await {
+            eprintln!("Error responding to slash command: {:?}", why);
         }
+        dispatch(command, context, msg).await;
     }
     async fn ready(&self, context: Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
+        // ready fires again on reconnects, the ready checks restored in `main` only need to be resumed once
+        if !self.timeouts_resumed.swap(true, Ordering::SeqCst) {
+            let data = context.data.read().await;
+            bot_service::resume_ready_timeouts(&context, &data);
+        }
+        {
+            let data = context.data.read().await;
+            let config: &Config = data.get::<Config>().unwrap();
+            let maps: &Vec<MapEntry> = data.get::<Maps>().unwrap();
+            for guild in &ready.guilds {
+                slash_commands::register(&context.http, config, guild.id(), maps).await;
+            }
+        }
+        log_game_server_status(&context).await;
         autoclear_queue(&context).await;
     }
 }
@@ -240,70 +472,138 @@ impl EventHandler for Handler {
 #[tokio::main]
 async fn main() -> () {
     let config = read_config().await.unwrap();
+    if !(1..=5).contains(&bot_service::team_size(&config)) {
+        panic!("`team_size` must be between 1 and 5");
+    }
+    if config.map_votes_per_player == Some(0) {
+        panic!("`map_votes_per_player` must be at least 1");
+    }
+    if config.map_vote_size.is_some_and(|size| size < 2) {
+        panic!("`map_vote_size` must be at least 2");
+    }
+    if config.webhook.is_some() && bot_service::webhook_authorization_header(&config).is_none() {
+        panic!("`webhook` requires `scrimbot_api_user` and `scrimbot_api_password` to authenticate the match end webhook");
+    }
+    let pick_order = bot_service::pick_order(&config);
+    if pick_order.is_empty() || !pick_order.chars().all(|c| c == 'A' || c == 'B') {
+        panic!("`pick_order` must be `alternate`, `snake` or a pattern of A's and B's i.e. `ABBABA`");
+    }
+    let db = Database::open().expect("Error opening database");
     let token = &config.discord.token;
     let framework = StandardFramework::new();
-    let mut client = Client::builder(&token)
-        .event_handler(Handler {})
-        .framework(framework)
+    let mut client_builder = Client::builder(token)
+        .event_handler(Handler { timeouts_resumed: AtomicBool::new(false) })
+        .framework(framework);
+    if let Some(application_id) = config.discord.application_id {
+        client_builder = client_builder.application_id(application_id);
+    }
+    let mut client = client_builder
         .await
         .expect("Error creating client");
     {
         let mut data = client.data.write().await;
-        data.insert::<UserQueue>(Vec::new());
-        data.insert::<ReadyQueue>(Vec::new());
-        data.insert::<QueueMessages>(HashMap::new());
+        data.insert::<Lobbies>(HashMap::new());
+        data.insert::<GameServer>(create_game_server_provider(&config));
+        data.insert::<LiveMatches>(HashMap::new());
         data.insert::<Config>(config);
-        data.insert::<SteamIdCache>(read_steam_ids().await.unwrap());
-        data.insert::<TeamNameCache>(read_teamnames().await.unwrap());
-        data.insert::<BotState>(StateContainer { state: State::Queue });
-        data.insert::<Maps>(read_maps().await.unwrap());
-        data.insert::<Draft>(Draft {
-            captain_a: None,
-            captain_b: None,
-            current_picker: None,
-            team_a: Vec::new(),
-            team_b: Vec::new(),
-            team_b_start_side: String::from(""),
-        });
+        data.insert::<SteamIdCache>(db.steam_ids().expect("Error reading steamIDs from database"));
+        data.insert::<TeamNameCache>(db.team_names().expect("Error reading team names from database"));
+        data.insert::<Maps>(db.maps().expect("Error reading maps from database"));
+        data.insert::<Db>(db);
+        // restore before the webhook server starts, a webhook saving the state first would overwrite the snapshot
+        if persistence::restore_state(&mut data) {
+            println!("Restored queue & match state from previous session");
+        }
     }
+    start_webhook_server(&client).await;
     if let Err(why) = client.start().await {
         eprintln!("Client error: {:?}", why);
     }
 }
 
-async fn read_config() -> Result<Config, serde_yaml::Error> {
-    let yaml = std::fs::read_to_string("config.yaml").unwrap();
-    let config: Config = serde_yaml::from_str(&yaml)?;
-    Ok(config)
+async fn dispatch(command: Command, context: Context, msg: CommandMessage) {
+    match command {
+        Command::JOIN => bot_service::handle_join(&context, &msg, &msg.author).await,
+        Command::LEAVE => bot_service::handle_leave(context, msg).await,
+        Command::QUEUE => bot_service::handle_list(context, msg).await,
+        Command::START => bot_service::handle_start(context, msg).await,
+        Command::STEAMID => bot_service::handle_steam_id(context, msg).await,
+        Command::MAPS => bot_service::handle_map_list(context, msg).await,
+        Command::STATS => bot_service::handle_stats(context, msg).await,
+        Command::TEAMNAME => bot_service::handle_teamname(context, msg).await,
+        Command::KICK => bot_service::handle_kick(context, msg).await,
+        Command::SUB => bot_service::handle_sub(context, msg).await,
+        Command::CANCEL => bot_service::handle_cancel(context, msg).await,
+        Command::ADDMAP => bot_service::handle_add_map(context, msg).await,
+        Command::REMOVEMAP => bot_service::handle_remove_map(context, msg).await,
+        Command::ENABLEMAP => bot_service::handle_enable_map(context, msg, true).await,
+        Command::DISABLEMAP => bot_service::handle_enable_map(context, msg, false).await,
+        Command::CAPTAIN => bot_service::handle_captain(context, msg).await,
+        Command::PICK => bot_service::handle_pick(context, msg).await,
+        Command::BAN => bot_service::handle_ban(context, msg).await,
+        Command::READY => bot_service::handle_ready(context, msg).await,
+        Command::UNREADY => bot_service::handle_unready(context, msg).await,
+        Command::CT => bot_service::handle_ct_option(context, msg).await,
+        Command::T => bot_service::handle_t_option(context, msg).await,
+        Command::READYLIST => bot_service::handle_ready_list(context, msg).await,
+        Command::RECOVERQUEUE => bot_service::handle_recover_queue(context, msg).await,
+        Command::CLEAR => bot_service::handle_clear(context, msg).await,
+        Command::HISTORY => bot_service::handle_history(context, msg).await,
+        Command::MATCH => bot_service::handle_match(context, msg).await,
+        Command::HELP => bot_service::handle_help(context, msg).await,
+        Command::UNKNOWN => bot_service::handle_unknown(context, msg).await,
+    }
+}
+
+/// Text commands stay on unless `discord.text_commands` is turned off, i.e. once everyone is used to the slash commands
+async fn text_commands_enabled(context: &Context) -> bool {
+    let data = context.data.read().await;
+    let config: &Config = data.get::<Config>().unwrap();
+    config.discord.text_commands.unwrap_or(true)
 }
 
-async fn read_steam_ids() -> Result<HashMap<u64, String>, serde_json::Error> {
-    if std::fs::read("steam-ids.json").is_ok() {
-        let json_str = std::fs::read_to_string("steam-ids.json").unwrap();
-        let json = serde_json::from_str(&json_str).unwrap();
-        Ok(json)
+fn create_game_server_provider(config: &Config) -> Arc<dyn GameServerProvider> {
+    if let Some(rcon) = &config.rcon {
+        Arc::new(RconProvider::new(rcon, &config.server))
+    } else if let Some(dathost) = &config.dathost {
+        Arc::new(DathostProvider::new(dathost))
     } else {
-        Ok(HashMap::new())
+        panic!("Either a `dathost` or `rcon` section is required in config.yaml");
     }
 }
 
-async fn read_teamnames() -> Result<HashMap<u64, String>, serde_json::Error> {
-    if std::fs::read("teamnames.json").is_ok() {
-        let json_str = std::fs::read_to_string("teamnames.json").unwrap();
-        let json = serde_json::from_str(&json_str).unwrap();
-        Ok(json)
-    } else {
-        Ok(HashMap::new())
+async fn start_webhook_server(client: &Client) {
+    let data = client.data.read().await;
+    let config: &Config = data.get::<Config>().unwrap();
+    if let Some(webhook) = &config.webhook {
+        let bind_address = webhook.bind_address.parse().expect("Invalid webhook bind_address");
+        let server = match webhook::bind(&bind_address) {
+            Ok(server) => server,
+            Err(why) => panic!("Unable to bind the webhook to {}: {}", bind_address, why),
+        };
+        println!("Match end webhook listening on {}", bind_address);
+        tokio::spawn(webhook::serve(server, client.cache_and_http.http.clone(), client.data.clone()));
     }
 }
 
-async fn read_maps() -> Result<Vec<String>, serde_json::Error> {
-    if std::fs::read("maps.json").is_ok() {
-        let json_str = std::fs::read_to_string("maps.json").unwrap();
-        let json = serde_json::from_str(&json_str).unwrap();
-        Ok(json)
-    } else {
-        Ok(Vec::new())
+async fn read_config() -> Result<Config, serde_yaml::Error> {
+    let yaml = std::fs::read_to_string("config.yaml").unwrap();
+    let config: Config = serde_yaml::from_str(&yaml)?;
+    Ok(config)
+}
+
+async fn log_game_server_status(context: &Context) {
+    let data = context.data.read().await;
+    let config: &Config = data.get::<Config>().unwrap();
+    let game_server = data.get::<GameServer>().unwrap();
+    let lobby_servers = config.lobbies.iter().flatten().filter_map(|l| l.server.as_ref());
+    let pool_servers = config.servers.iter().flatten();
+    for server in std::iter::once(&config.server).chain(pool_servers).chain(lobby_servers) {
+        match game_server.status(server).await {
+            Ok(status) => println!("Game server {} is {} with {} player(s) online",
+                                   &server.id, if status.on { "on" } else { "off" }, status.players_online),
+            Err(why) => eprintln!("Unable to get game server {} status: {}", &server.id, why),
+        }
     }
 }
 
@@ -320,10 +620,13 @@ async fn autoclear_queue(context: &Context) {
             task::sleep(CoreDuration::from_millis(time_between.num_milliseconds() as u64)).await;
             {
                 let mut data = context.data.write().await;
-                let user_queue: &mut Vec<User> = &mut data.get_mut::<UserQueue>().unwrap();
-                user_queue.clear();
-                let queued_msgs: &mut HashMap<u64, String> = data.get_mut::<QueueMessages>().unwrap();
-                queued_msgs.clear();
+                let lobbies: &mut HashMap<u64, Lobby> = data.get_mut::<Lobbies>().unwrap();
+                for lobby in lobbies.values_mut() {
+                    lobby.user_queue.clear();
+                    lobby.waitlist.clear();
+                    lobby.queue_messages.clear();
+                }
+                persistence::save_state(&data);
             }
         }
     }
@@ -334,3 +637,18 @@ async fn get_autoclear_hour(client: &Context) -> Option<u32> {
     let config: &Config = &data.get::<Config>().unwrap();
     config.autoclear_hour
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_commands_need_their_exact_name() {
+        assert!(matches!(Command::from_str(".join"), Ok(Command::JOIN)));
+        assert!(matches!(Command::from_str(".list"), Ok(Command::QUEUE)));
+        assert!(matches!(Command::from_str(".ready"), Ok(Command::READY)));
+        for abbreviation in [".", ".j", ".r", ".rea", ".h", ".l"] {
+            assert!(Command::from_str(abbreviation).is_err(), "{} should not match a command", abbreviation);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serenity::prelude::TypeMap;
+
+use crate::{LiveMatch, LiveMatches, Lobbies, Lobby, State};
+
+const STATE_FILE: &str = "state.json";
+
+/// Everything needed to pick a scrim back up after the bot restarts
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    lobbies: HashMap<u64, Lobby>,
+    live_matches: HashMap<String, LiveMatch>,
+}
+
+/// Writes the current match lifecycle state to disk, call after every state transition
+pub(crate) fn save_state(data: &TypeMap) {
+    let snapshot = Snapshot {
+        lobbies: data.get::<Lobbies>().unwrap().clone(),
+        live_matches: data.get::<LiveMatches>().unwrap().clone(),
+    };
+    let json = match serde_json::to_string(&snapshot) {
+        Ok(json) => json,
+        Err(why) => {
+            eprintln!("Error serializing state: {:?}", why);
+            return;
+        }
+    };
+    // write to a temp file first so a crash mid-write can't leave a truncated snapshot behind
+    let tmp_file = format!("{}.tmp", STATE_FILE);
+    if let Err(why) = std::fs::write(&tmp_file, json).and_then(|_| std::fs::rename(&tmp_file, STATE_FILE)) {
+        eprintln!("Error writing to {}: {:?}", STATE_FILE, why);
+    }
+}
+
+/// Restores the last saved state, if any. Returns `true` when a snapshot was loaded.
+pub(crate) fn restore_state(data: &mut TypeMap) -> bool {
+    let json = match std::fs::read_to_string(STATE_FILE) {
+        Ok(json) => json,
+        Err(_) => return false,
+    };
+    let mut snapshot: Snapshot = match serde_json::from_str(&json) {
+        Ok(snapshot) => snapshot,
+        Err(why) => {
+            eprintln!("Error reading {}, starting with an empty queue: {:?}", STATE_FILE, why);
+            return false;
+        }
+    };
+    for lobby in snapshot.lobbies.values_mut() {
+        if lobby.state.state == State::MapPick || lobby.state.state == State::RandomTeams {
+            // the map and re-roll votes only live in the `.start` command that was interrupted, so they have to be redone
+            println!("Vote was interrupted by a restart, resetting to the queue phase");
+            lobby.state.state = State::Queue;
+        }
+        if lobby.state.state == State::Live && lobby.series.is_none() {
+            // the restart interrupted the launch of a single match, its players have to `.ready` again
+            println!("Match launch was interrupted by a restart, resetting to the ready phase");
+            lobby.state.state = State::Ready;
+            lobby.ready_queue.clear();
+            lobby.ready_deadline = None;
+            lobby.server_id = None;
+        }
+    }
+    data.insert::<Lobbies>(snapshot.lobbies);
+    data.insert::<LiveMatches>(snapshot.live_matches);
+    true
+}
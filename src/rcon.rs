@@ -0,0 +1,254 @@
+use std::io;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+use regex::Regex;
+use serenity::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+use crate::{RconConfig, ServerConfig};
+use crate::db::MapEntry;
+use crate::game_server::{GameServerProvider, MatchSettings, ServerStatus};
+
+// https://developer.valvesoftware.com/wiki/Source_RCON_Protocol
+const SERVERDATA_AUTH: i32 = 3;
+const SERVERDATA_AUTH_RESPONSE: i32 = 2;
+const SERVERDATA_EXECCOMMAND: i32 = 2;
+const SERVERDATA_RESPONSE_VALUE: i32 = 0;
+const MAX_PACKET_SIZE: i32 = 4096 + 10;
+const RCON_TIMEOUT: Duration = Duration::from_secs(10);
+const MAP_LOAD_DELAY: Duration = Duration::from_secs(15);
+
+/// Talks Source RCON directly to a self-hosted dedicated server.
+/// A server is reached on its `rcon_address` if set, otherwise on its connect url. `rcon.address` is kept as the
+/// address of the top level `server` for configs written before servers had their own.
+pub(crate) struct RconProvider {
+    address: Option<String>,
+    default_server_id: String,
+    password: String,
+    match_config: Option<String>,
+    next_id: AtomicI32,
+}
+
+struct RconPacket {
+    id: i32,
+    packet_type: i32,
+    body: String,
+}
+
+impl RconProvider {
+    pub(crate) fn new(config: &RconConfig, default_server: &ServerConfig) -> RconProvider {
+        RconProvider {
+            address: config.address.clone(),
+            default_server_id: String::from(&default_server.id),
+            password: String::from(&config.password),
+            match_config: config.match_config.clone(),
+            next_id: AtomicI32::new(1),
+        }
+    }
+
+    fn address<'a>(&'a self, server: &'a ServerConfig) -> &'a str {
+        let default_address = self.address.as_deref().filter(|_| server.id == self.default_server_id);
+        server.rcon_address.as_deref().or(default_address).unwrap_or(&server.url)
+    }
+
+    fn next_id(&self) -> i32 {
+        self.next_id.fetch_add(2, Ordering::Relaxed)
+    }
+
+    /// Opens a connection, authenticates and runs a single command, returning the full response body
+    async fn execute(&self, server: &ServerConfig, command: &str) -> Result<String, String> {
+        let address = self.address(server);
+        match timeout(RCON_TIMEOUT, self.execute_on(address, command)).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(why)) => Err(format!("RCON command `{}` on {} failed: {}", command, address, why)),
+            Err(_) => Err(format!("RCON command `{}` on {} timed out", command, address)),
+        }
+    }
+
+    async fn execute_on(&self, address: &str, command: &str) -> io::Result<String> {
+        let mut stream = TcpStream::connect(address).await?;
+        let auth_id = self.next_id();
+        write_packet(&mut stream, auth_id, SERVERDATA_AUTH, &self.password).await?;
+        loop {
+            // the server sends an empty SERVERDATA_RESPONSE_VALUE before the auth response
+            let packet = read_packet(&mut stream).await?;
+            if packet.packet_type != SERVERDATA_AUTH_RESPONSE { continue; }
+            if packet.id != auth_id {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "authentication failed, check the rcon password"));
+            }
+            break;
+        }
+        let command_id = self.next_id();
+        let marker_id = command_id + 1;
+        write_packet(&mut stream, command_id, SERVERDATA_EXECCOMMAND, command).await?;
+        // responses can be split over multiple packets, an empty response value is mirrored
+        // back once the server is done so we know where the command output ends
+        write_packet(&mut stream, marker_id, SERVERDATA_RESPONSE_VALUE, "").await?;
+        let mut response = String::new();
+        loop {
+            let packet = read_packet(&mut stream).await?;
+            if packet.id == marker_id { break; }
+            if packet.id == command_id && packet.packet_type == SERVERDATA_RESPONSE_VALUE {
+                response.push_str(&packet.body);
+            }
+        }
+        Ok(response)
+    }
+}
+
+async fn write_packet(stream: &mut TcpStream, id: i32, packet_type: i32, body: &str) -> io::Result<()> {
+    let mut packet: Vec<u8> = Vec::with_capacity(body.len() + 14);
+    packet.extend_from_slice(&(body.len() as i32 + 10).to_le_bytes());
+    packet.extend_from_slice(&id.to_le_bytes());
+    packet.extend_from_slice(&packet_type.to_le_bytes());
+    packet.extend_from_slice(body.as_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    stream.write_all(&packet).await
+}
+
+async fn read_packet(stream: &mut TcpStream) -> io::Result<RconPacket> {
+    let size = stream.read_i32_le().await?;
+    if !(10..=MAX_PACKET_SIZE).contains(&size) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid packet size {}", size)));
+    }
+    let id = stream.read_i32_le().await?;
+    let packet_type = stream.read_i32_le().await?;
+    let mut body = vec![0; size as usize - 8];
+    stream.read_exact(&mut body).await?;
+    // strip the body and packet null terminators
+    body.truncate(body.len() - 2);
+    Ok(RconPacket { id, packet_type, body: String::from_utf8_lossy(&body).into_owned() })
+}
+
+#[async_trait]
+impl GameServerProvider for RconProvider {
+    async fn set_start_map(&self, server: &ServerConfig, map: &MapEntry) -> Result<(), String> {
+        let command = match map.workshop_id {
+            Some(workshop_id) => format!("host_workshop_map {}", workshop_id),
+            None => format!("changelevel {}", &map.map_name),
+        };
+        self.execute(server, &command).await?;
+        // the match is started right after the map is set, give the server time to load it first
+        sleep(MAP_LOAD_DELAY).await;
+        Ok(())
+    }
+
+    async fn start_match(&self, server: &ServerConfig, _settings: &MatchSettings) -> Result<Option<String>, String> {
+        if let Some(match_config) = &self.match_config {
+            self.execute(server, &format!("exec {}", match_config)).await?;
+        }
+        self.execute(server, "mp_warmup_end").await?;
+        self.execute(server, "mp_restartgame 1").await?;
+        Ok(None)
+    }
+
+    async fn send_console(&self, server: &ServerConfig, line: &str) -> Result<(), String> {
+        self.execute(server, line).await?;
+        Ok(())
+    }
+
+    async fn status(&self, server: &ServerConfig) -> Result<ServerStatus, String> {
+        let status = match self.execute(server, "status").await {
+            Ok(status) => status,
+            Err(why) => {
+                // unlike a hosting platform there is nothing else to ask, a server that doesn't answer counts as off
+                eprintln!("{}", why);
+                return Ok(ServerStatus { on: false, players_online: 0 });
+            }
+        };
+        // i.e. `players : 3 humans, 0 bots (10/0 max) (not hibernating)`
+        let players_regex = Regex::new("players\\s*:\\s*(\\d+) humans").unwrap();
+        let players_online = players_regex.captures(&status)
+            .and_then(|c| c[1].parse::<u64>().ok())
+            .unwrap_or(0);
+        Ok(ServerStatus { on: true, players_online })
+    }
+
+    fn reports_match_end(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn provider() -> RconProvider {
+        RconProvider::new(&RconConfig { address: None, password: String::from("secret"), match_config: None }, &server("server-1", None))
+    }
+
+    fn server(id: &str, rcon_address: Option<&str>) -> ServerConfig {
+        ServerConfig { id: String::from(id), url: String::from("127.0.0.1:27015"), rcon_address: rcon_address.map(String::from) }
+    }
+
+    /// Accepts a single connection and answers the auth request with `auth_id`, or the request's own id if `None`,
+    /// then replies to the command with each of `responses` followed by the end of response marker
+    async fn fake_server(auth_id: Option<i32>, responses: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let auth = read_packet(&mut stream).await.unwrap();
+            assert_eq!(auth.packet_type, SERVERDATA_AUTH);
+            assert_eq!(auth.body, "secret");
+            write_packet(&mut stream, auth.id, SERVERDATA_RESPONSE_VALUE, "").await.unwrap();
+            write_packet(&mut stream, auth_id.unwrap_or(auth.id), SERVERDATA_AUTH_RESPONSE, "").await.unwrap();
+            let command = match read_packet(&mut stream).await {
+                Ok(command) => command,
+                Err(_) => return,
+            };
+            assert_eq!(command.packet_type, SERVERDATA_EXECCOMMAND);
+            let marker = read_packet(&mut stream).await.unwrap();
+            for response in responses {
+                write_packet(&mut stream, command.id, SERVERDATA_RESPONSE_VALUE, response).await.unwrap();
+            }
+            write_packet(&mut stream, marker.id, SERVERDATA_RESPONSE_VALUE, "").await.unwrap();
+        });
+        address
+    }
+
+    #[test]
+    fn rcon_address_only_overrides_the_top_level_server() {
+        let provider = RconProvider::new(&RconConfig { address: Some(String::from("10.0.0.1:27015")), password: String::new(), match_config: None }, &server("main", None));
+        assert_eq!(provider.address(&server("main", None)), "10.0.0.1:27015");
+        assert_eq!(provider.address(&server("pool-1", None)), "127.0.0.1:27015");
+        assert_eq!(provider.address(&server("pool-2", Some("10.0.0.2:27016"))), "10.0.0.2:27016");
+    }
+
+    #[tokio::test]
+    async fn executes_a_command_after_authenticating() {
+        let address = fake_server(None, vec!["hostname: test"]).await;
+        assert_eq!(provider().execute_on(&address, "hostname").await.unwrap(), "hostname: test");
+    }
+
+    #[tokio::test]
+    async fn fails_when_authentication_is_rejected() {
+        let address = fake_server(Some(-1), vec![]).await;
+        let error = provider().execute_on(&address, "status").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn joins_a_response_split_over_several_packets() {
+        let address = fake_server(None, vec!["players : 3 humans", ", 0 bots", " (10/0 max)"]).await;
+        assert_eq!(provider().execute_on(&address, "status").await.unwrap(), "players : 3 humans, 0 bots (10/0 max)");
+    }
+
+    #[tokio::test]
+    async fn rejects_an_invalid_packet_size() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_packet(&mut stream).await.unwrap();
+            stream.write_all(&5i32.to_le_bytes()).await.unwrap();
+        });
+        let error = provider().execute_on(&address, "status").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
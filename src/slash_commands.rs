@@ -0,0 +1,182 @@
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommandOption};
+use serenity::http::Http;
+use serenity::model::id::GuildId;
+use serenity::model::interactions::application_command::{ApplicationCommandInteraction, ApplicationCommandInteractionDataOptionValue, ApplicationCommandOptionType};
+use serenity::model::user::User;
+
+use crate::{Command, CommandMessage, Config};
+use crate::db::MapEntry;
+
+/// Discord allows at most 25 choices per option, the rest of the map pool can still be typed with the text commands
+const MAX_CHOICES: usize = 25;
+
+/// Registers the slash commands in a guild, replacing any that were registered before.
+/// Does nothing unless `discord.application_id` is configured. Call again whenever the map pool changes
+/// so the map choices stay up to date.
+pub(crate) async fn register(http: &Http, config: &Config, guild_id: GuildId, maps: &[MapEntry]) {
+    if config.discord.application_id.is_none() { return; }
+    let result = guild_id.set_application_commands(http, |commands| {
+        commands
+            .add_application_command(command("join", "Join the queue")
+                .add_option(option("message", "Optional message shown in the queue list (max 50 characters)", ApplicationCommandOptionType::String, false))
+                .to_owned())
+            .add_application_command(command("leave", "Leave the queue"))
+            .add_application_command(command("list", "List all users in the queue"))
+            .add_application_command(command("steamid", "Set your steamID")
+                .add_option(option("steamid", "Your steamID i.e. STEAM_0:1:12345678", ApplicationCommandOptionType::String, true))
+                .to_owned())
+            .add_application_command(command("maps", "Lists all maps available for play"))
+            .add_application_command(command("stats", "Lists your statistics from scrimbot-api")
+                .add_option(option("ranking", "Show a top 10 ranking instead of your own stats", ApplicationCommandOptionType::String, false)
+                    .add_string_choice("top10", "top10")
+                    .add_string_choice("maps", "maps")
+                    .to_owned())
+                .add_option(option("months", "Only include the past X months", ApplicationCommandOptionType::Integer, false)
+                    .min_int_value(1)
+                    .max_int_value(9)
+                    .to_owned())
+                .add_option(option("map", "Only include matches on this map i.e. de_dust2", ApplicationCommandOptionType::String, false))
+                .to_owned())
+            .add_application_command(command("teamname", "Sets a custom team name for when you are a captain")
+                .add_option(option("name", "Your team name (max 18 characters)", ApplicationCommandOptionType::String, true))
+                .to_owned())
+            .add_application_command(command("history", "Lists recently played matches")
+                .add_option(option("count", "Number of matches to show", ApplicationCommandOptionType::Integer, false)
+                    .min_int_value(1)
+                    .max_int_value(20)
+                    .to_owned())
+                .to_owned())
+            .add_application_command(command("match", "Shows the teams, sides and result of a match from /history")
+                .add_option(option("id", "Match id", ApplicationCommandOptionType::Integer, true))
+                .to_owned())
+            .add_application_command(command("captain", "Add yourself as a captain"))
+            .add_application_command(command("pick", "Pick a player for your team, or a map during a BO3 veto, when you are a captain")
+                .add_option(option("player", "The player to pick", ApplicationCommandOptionType::User, false))
+                .add_option(map_choices(option("map", "The map to pick during a BO3 veto", ApplicationCommandOptionType::String, false), maps, true))
+                .to_owned())
+            .add_application_command(command("ban", "Ban a map during the map veto when you are a captain")
+                .add_option(map_choices(option("map", "The map to ban", ApplicationCommandOptionType::String, true), maps, true))
+                .to_owned())
+            .add_application_command(command("ready", "Ready up once the draft is completed"))
+            .add_application_command(command("unready", "Cancel your ready status"))
+            .add_application_command(command("readylist", "Lists players not readied up"))
+            .add_application_command(command("ct", "Start on the CT side when you are captain B"))
+            .add_application_command(command("t", "Start on the T side when you are captain B"))
+            .add_application_command(command("help", "DMs you the list of commands"))
+            .add_application_command(command("start", "Admin: start the match setup process")
+                .add_option(option("mode", "How teams are made, defaults to a captain pick & draft", ApplicationCommandOptionType::String, false)
+                    .add_string_choice("balanced", "balanced")
+                    .add_string_choice("random", "random")
+                    .to_owned())
+                .add_option(option("veto", "Let the captains veto maps instead of the map vote", ApplicationCommandOptionType::String, false)
+                    .add_string_choice("bo1", "veto")
+                    .add_string_choice("bo3", "bo3")
+                    .to_owned())
+                .to_owned())
+            .add_application_command(command("kick", "Admin: kick a player from the queue")
+                .add_option(option("player", "The player to kick", ApplicationCommandOptionType::User, true))
+                .to_owned())
+            .add_application_command(command("sub", "Admin or captain: replace a player after the match setup has started")
+                .add_option(option("out", "The player to replace", ApplicationCommandOptionType::User, true))
+                .add_option(option("in", "The substitute", ApplicationCommandOptionType::User, true))
+                .to_owned())
+            .add_application_command(command("addmap", "Admin: add a map to the map vote")
+                .add_option(option("map", "Map name i.e. de_dust2, must be present on the server unless it has a workshop id", ApplicationCommandOptionType::String, true))
+                .add_option(option("workshop_id", "Steam Workshop id to load the map from", ApplicationCommandOptionType::String, false))
+                .add_option(option("name", "Name shown in the map vote, defaults to the map name", ApplicationCommandOptionType::String, false))
+                .to_owned())
+            .add_application_command(command("removemap", "Admin: remove a map from the map vote")
+                .add_option(map_choices(option("map", "The map to remove", ApplicationCommandOptionType::String, true), maps, false))
+                .to_owned())
+            .add_application_command(command("enablemap", "Admin: put a disabled map back in the map vote")
+                .add_option(map_choices(option("map", "The map to enable", ApplicationCommandOptionType::String, true), maps, false))
+                .to_owned())
+            .add_application_command(command("disablemap", "Admin: leave a map out of the map vote without removing it")
+                .add_option(map_choices(option("map", "The map to disable", ApplicationCommandOptionType::String, true), maps, false))
+                .to_owned())
+            .add_application_command(recover_queue_command())
+            .add_application_command(command("clear", "Admin: clear the queue"))
+            .add_application_command(command("cancel", "Admin: cancel the match setup process"))
+    }).await;
+    if let Err(why) = result {
+        eprintln!("Error registering slash commands in guild {}: {:?}", guild_id, why);
+    }
+}
+
+/// Turns a slash command into the equivalent text command, so both are handled by the same code.
+/// The rebuilt text is also posted as the response to the interaction.
+pub(crate) fn to_command_message(interaction: &ApplicationCommandInteraction) -> Option<(Command, CommandMessage)> {
+    let data = &interaction.data;
+    let mut content = format!(".{}", data.name);
+    let mut mentions: Vec<User> = Vec::new();
+    let mut push_user = |content: &mut String, user: &User| {
+        content.push_str(&format!(" <@{}>", user.id));
+        mentions.push(user.clone());
+    };
+    for option in &data.options {
+        match (data.name.as_str(), &option.resolved) {
+            (_, Some(ApplicationCommandInteractionDataOptionValue::User(user, _))) => push_user(&mut content, user),
+            ("join", Some(ApplicationCommandInteractionDataOptionValue::String(message))) => content.push_str(&format!(" \"{}\"", message)),
+            ("addmap", Some(ApplicationCommandInteractionDataOptionValue::String(value))) if option.name == "name" => content.push_str(&format!(" \"{}\"", value)),
+            ("stats", Some(ApplicationCommandInteractionDataOptionValue::Integer(months))) => content.push_str(&format!(" {}m", months)),
+            ("stats", Some(ApplicationCommandInteractionDataOptionValue::String(value))) if option.name == "map" => content.push_str(&format!(" \"{}\"", value)),
+            (_, Some(ApplicationCommandInteractionDataOptionValue::String(value))) => content.push_str(&format!(" {}", value)),
+            (_, Some(ApplicationCommandInteractionDataOptionValue::Integer(value))) => content.push_str(&format!(" {}", value)),
+            _ => {}
+        }
+    }
+    // `.stats` expects the ranking before the month filter, which Discord doesn't guarantee
+    if data.name == "stats" {
+        content = reorder_stats_args(&content);
+    }
+    let command = match data.name.as_str() {
+        // `.list` is the text alias of `.queue`
+        "list" => Command::QUEUE,
+        name => format!(".{}", name).parse::<Command>().ok()?,
+    };
+    Some((command, CommandMessage {
+        author: interaction.user.clone(),
+        channel_id: interaction.channel_id,
+        guild_id: interaction.guild_id,
+        content,
+        mentions,
+    }))
+}
+
+fn reorder_stats_args(content: &str) -> String {
+    let args: Vec<&str> = content.split(' ').skip(1).collect();
+    let ranking = args.iter().filter(|a| **a == "top10" || **a == "maps");
+    let months = args.iter().filter(|a| a.ends_with('m') && !a.starts_with('"') && **a != "maps");
+    let map = args.iter().filter(|a| a.starts_with('"'));
+    std::iter::once(&".stats").chain(ranking).chain(months).chain(map)
+        .copied()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn command(name: &str, description: &str) -> CreateApplicationCommand {
+    let mut command = CreateApplicationCommand::default();
+    command.name(name).description(description);
+    command
+}
+
+fn option(name: &str, description: &str, kind: ApplicationCommandOptionType, required: bool) -> CreateApplicationCommandOption {
+    let mut option = CreateApplicationCommandOption::default();
+    option.name(name).description(description).kind(kind).required(required);
+    option
+}
+
+fn map_choices(mut option: CreateApplicationCommandOption, maps: &[MapEntry], enabled_only: bool) -> CreateApplicationCommandOption {
+    for map in maps.iter().filter(|m| m.enabled || !enabled_only).take(MAX_CHOICES) {
+        option.add_string_choice(&map.name, &map.name);
+    }
+    option
+}
+
+fn recover_queue_command() -> CreateApplicationCommand {
+    let mut command = command("recoverqueue", "Admin: manually set the queue");
+    for i in 1..=10 {
+        command.add_option(option(&format!("player{}", i), "A player to add to the queue", ApplicationCommandOptionType::User, i == 1));
+    }
+    command
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::body::HttpBody;
+use hyper::server::Builder;
+use hyper::server::conn::AddrIncoming;
+use hyper::header::AUTHORIZATION;
+use hyper::service::{make_service_fn, service_fn};
+use serde::Deserialize;
+use serenity::http::Http;
+use serenity::model::id::ChannelId;
+use serenity::prelude::{RwLock, TypeMap};
+use serenity::utils::MessageBuilder;
+
+use crate::{Config, Db, LiveMatches, SteamIdCache};
+use crate::bot_service::{series_map_ended, webhook_authorization_header};
+use crate::db::Database;
+use crate::persistence::save_state;
+
+/// DatHost's match objects are a few kilobytes, anything much larger isn't a match end webhook
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// The match object DatHost posts to `match_end_webhook_url` once a match is over
+#[derive(Deserialize)]
+struct MatchEnd {
+    id: String,
+    #[serde(default)]
+    team1_stats: TeamStats,
+    #[serde(default)]
+    team2_stats: TeamStats,
+    #[serde(default)]
+    team1_steam_ids: Vec<String>,
+    #[serde(default)]
+    team2_steam_ids: Vec<String>,
+    #[serde(default)]
+    player_stats: Vec<PlayerStats>,
+}
+
+#[derive(Deserialize, Default)]
+struct TeamStats {
+    #[serde(default)]
+    score: u64,
+}
+
+#[derive(Deserialize)]
+struct PlayerStats {
+    steam_id: String,
+    #[serde(default)]
+    kills: u64,
+    #[serde(default)]
+    deaths: u64,
+    #[serde(default)]
+    assists: u64,
+    #[serde(default)]
+    mvps: u64,
+}
+
+/// Binds the webhook's address up front, so an address that's already in use is reported before anything is spawned
+pub(crate) fn bind(bind_address: &SocketAddr) -> hyper::Result<Builder<AddrIncoming>> {
+    Server::try_bind(bind_address)
+}
+
+pub(crate) async fn serve(server: Builder<AddrIncoming>, http: Arc<Http>, data: Arc<RwLock<TypeMap>>) {
+    let make_service = make_service_fn(move |_conn| {
+        let http = http.clone();
+        let data = data.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| handle_request(req, http.clone(), data.clone())))
+        }
+    });
+    if let Err(why) = server.serve(make_service).await {
+        eprintln!("Webhook server error: {:?}", why);
+    }
+}
+
+async fn handle_request(req: Request<Body>, http: Arc<Http>, data: Arc<RwLock<TypeMap>>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::POST {
+        return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    {
+        let data = data.read().await;
+        let config: &Config = data.get::<Config>().unwrap();
+        let expected = match webhook_authorization_header(config) {
+            Some(expected) => expected,
+            None => {
+                eprintln!("Rejected match end webhook, no authorization header is configured");
+                return Ok(status_response(StatusCode::UNAUTHORIZED));
+            }
+        };
+        let received = req.headers().get(AUTHORIZATION)
+            .map(|h| h.as_bytes())
+            .unwrap_or_default();
+        if !constant_time_eq(received, expected.as_bytes()) {
+            eprintln!("Rejected match end webhook with invalid authorization header");
+            return Ok(status_response(StatusCode::UNAUTHORIZED));
+        }
+    }
+    let body = match read_body(req.into_body()).await {
+        Ok(body) => body,
+        Err(status) => return Ok(status_response(status)),
+    };
+    let match_end: MatchEnd = match serde_json::from_slice(&body) {
+        Ok(match_end) => match_end,
+        Err(why) => {
+            eprintln!("Error parsing match end webhook body: {:?}", why);
+            return Ok(status_response(StatusCode::BAD_REQUEST));
+        }
+    };
+    println!("Received match end webhook for match {}", &match_end.id);
+    post_scoreboard(&match_end, &http, &data).await;
+    Ok(status_response(StatusCode::OK))
+}
+
+async fn post_scoreboard(match_end: &MatchEnd, http: &Arc<Http>, data: &Arc<RwLock<TypeMap>>) {
+    let mut data = data.write().await;
+    let live_match = data.get_mut::<LiveMatches>().unwrap().remove(&match_end.id);
+    save_state(&data);
+    let config: &Config = data.get::<Config>().unwrap();
+    let fallback_channel_id = config.webhook.as_ref().and_then(|w| w.channel_id);
+    let series_map = live_match.is_some();
+    let (channel_id, team1_name, team2_name) = match live_match {
+        Some(live_match) => (live_match.channel_id, live_match.team1_name, live_match.team2_name),
+        None => match fallback_channel_id {
+            Some(channel_id) => (channel_id, String::from("Team 1"), String::from("Team 2")),
+            None => {
+                eprintln!("No channel to post results of match {} to", &match_end.id);
+                return;
+            }
+        }
+    };
+    let db: &Database = data.get::<Db>().unwrap();
+    if let Err(why) = db.record_match_result(&match_end.id, &team1_name, &team2_name, match_end.team1_stats.score, match_end.team2_stats.score) {
+        eprintln!("Error saving match result: {:?}", why);
+    }
+    let steam_id_cache: &HashMap<u64, String> = data.get::<SteamIdCache>().unwrap();
+    let player_name = |steam_id: &str| -> String {
+        // the cache may hold either STEAM_0 or STEAM_1 ids, DatHost always reports STEAM_1
+        steam_id_cache.iter()
+            .find(|(_, val)| val.len() > 7 && steam_id.len() > 7 && val[7..] == steam_id[7..])
+            .map(|(key, _)| format!("<@{}>", key))
+            .unwrap_or_else(|| String::from(steam_id))
+    };
+    let team_lines = |steam_ids: &Vec<String>| -> String {
+        match_end.player_stats
+            .iter()
+            .filter(|p| steam_ids.contains(&p.steam_id))
+            .map(|p| format!("- {} `{}/{}/{}`\n", player_name(&p.steam_id), p.kills, p.deaths, p.assists))
+            .collect()
+    };
+    let mut response = MessageBuilder::new();
+    response
+        .push_bold_line("Match has ended")
+        .push_line(format!("{} **{}** - **{}** {}", &team1_name, match_end.team1_stats.score, match_end.team2_stats.score, &team2_name))
+        .push_line("")
+        .push_bold_line(format!("{} (K/D/A):", &team1_name))
+        .push(team_lines(&match_end.team1_steam_ids))
+        .push_bold_line(format!("{} (K/D/A):", &team2_name))
+        .push(team_lines(&match_end.team2_steam_ids));
+    let mvp = match_end.player_stats
+        .iter()
+        .max_by(|x, y| x.mvps.cmp(&y.mvps).then(x.kills.cmp(&y.kills)));
+    if let Some(mvp) = mvp {
+        response.push_line("")
+            .push_line(format!("MVP: {} ({} kills, {} MVPs)", player_name(&mvp.steam_id), mvp.kills, mvp.mvps));
+    }
+    if let Err(why) = ChannelId(channel_id).say(http, response.build()).await {
+        eprintln!("Error sending message: {:?}", why);
+    }
+    if series_map {
+        series_map_ended(http, &mut data, ChannelId(channel_id), &match_end.id, match_end.team1_stats.score, match_end.team2_stats.score).await;
+    }
+}
+
+/// Reads the request body, giving up once it grows past `MAX_BODY_SIZE`
+async fn read_body(mut body: Body) -> Result<Vec<u8>, StatusCode> {
+    let mut bytes: Vec<u8> = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|why| {
+            eprintln!("Error reading match end webhook body: {:?}", why);
+            StatusCode::BAD_REQUEST
+        })?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            eprintln!("Rejected match end webhook with a body over {} bytes", MAX_BODY_SIZE);
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Compares in constant time, so the expected header can't be worked out from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() { return false; }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}